@@ -0,0 +1,319 @@
+use crate::console::{console_path, Frame};
+use crate::state;
+use crate::supervisor::SupervisorError;
+use crate::tty::{self, RawMode};
+use crate::Result;
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::libc;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::{isatty, pipe2, read, write};
+use std::error::Error;
+use std::fmt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+
+const STDIN: RawFd = 0;
+const STDOUT: RawFd = 1;
+const STDERR: RawFd = 2;
+const BUFFER_SIZE: usize = 4096;
+
+/// Signals we get that are meant for the container.
+const FORWARDED_SIGNALS: &[Signal] = &[
+  Signal::SIGINT,
+  Signal::SIGTERM,
+  Signal::SIGHUP,
+  Signal::SIGQUIT,
+];
+
+/// The signals we caught since they were last handled, a bit for each.
+static PENDING_SIGNALS: AtomicU64 = AtomicU64::new(0);
+/// The write end of a pipe the relay polls, so that every signal wakes it up,
+/// even one caught right before it starts polling.
+static WAKE_UP: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn handle_signal(signal: libc::c_int) {
+  PENDING_SIGNALS.fetch_or(1 << signal, Ordering::SeqCst);
+  // The write must not change the errno the interrupted code sees.
+  let errno = Errno::last();
+  let _ = write(WAKE_UP.load(Ordering::SeqCst), &[0]);
+  unsafe { *libc::__errno_location() = errno as i32 };
+}
+
+/// The key sequence that detaches from a container with a terminal.
+pub const DEFAULT_DETACH_KEYS: &str = "ctrl-p,ctrl-q";
+
+#[derive(Debug)]
+pub enum AttachError {
+  InvalidDetachKeys(String),
+}
+
+impl fmt::Display for AttachError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      AttachError::InvalidDetachKeys(keys) => write!(
+        f,
+        "Invalid detach keys {}, expected keys like ctrl-p,ctrl-q or a",
+        keys
+      ),
+    }
+  }
+}
+
+impl Error for AttachError {}
+
+/// How an attached session ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Disconnect {
+  /// The user typed the detach keys, the container keeps running.
+  Detached,
+  /// Every process of the container is gone, with the exit code of the
+  /// container.
+  Exited(i32),
+}
+
+/// Finds the detach key sequence in the input of the user. The keys that
+/// may start the sequence are held back until the next ones tell.
+pub struct DetachKeys {
+  keys: Vec<u8>,
+  matched: usize,
+}
+
+impl DetachKeys {
+  /// Parse a sequence like `ctrl-p,ctrl-q`. A key is either a character or
+  /// `ctrl-` and one of `a-z@[\]^_`.
+  pub fn parse(value: &str) -> Result<Self> {
+    let invalid = || Box::new(AttachError::InvalidDetachKeys(value.to_string()));
+    let mut keys = vec![];
+    for key in value.split(',') {
+      let key = key.trim().to_lowercase();
+      let bytes = key.as_bytes();
+      match bytes {
+        [byte] if byte.is_ascii() => keys.push(*byte),
+        [b'c', b't', b'r', b'l', b'-', byte] => match byte {
+          b'a'..=b'z' | b'@' | b'[' | b'\\' | b']' | b'^' | b'_' => keys.push(byte & 0x1f),
+          _ => return Err(invalid()),
+        },
+        _ => return Err(invalid()),
+      }
+    }
+    Ok(DetachKeys { keys, matched: 0 })
+  }
+
+  /// Get the input to forward, and whether it ended with the sequence.
+  fn scan(&mut self, input: &[u8]) -> (Vec<u8>, bool) {
+    let mut forward = vec![];
+    for &byte in input {
+      if byte == self.keys[self.matched] {
+        self.matched += 1;
+        if self.matched == self.keys.len() {
+          self.matched = 0;
+          return (forward, true);
+        }
+        continue;
+      }
+      forward.extend_from_slice(&self.keys[..self.matched]);
+      self.matched = 0;
+      if byte == self.keys[0] {
+        self.matched = 1;
+      } else {
+        forward.push(byte);
+      }
+    }
+    (forward, false)
+  }
+}
+
+/// Main entry for the attach subcommand. Connects our terminal to a running
+/// container.
+pub fn attach(args: &clap::ArgMatches<'static>) -> Result<()> {
+  let state = state::find_running(args.value_of("id").unwrap())?;
+  let detach_keys = DetachKeys::parse(args.value_of("detach-keys").unwrap_or(DEFAULT_DETACH_KEYS))?;
+  let console = UnixStream::connect(console_path(&state.id))?;
+  match relay(console, state.tty, state.interactive, detach_keys)? {
+    Disconnect::Detached => info!("Detached from the container {}", state.id),
+    Disconnect::Exited(code) => info!("The container {} exited with {}", state.id, code),
+  }
+  Ok(())
+}
+
+fn send_window_size(console: &mut UnixStream) -> Result<()> {
+  if let Some(size) = tty::window_size(STDIN) {
+    Frame::Resize(size.ws_row, size.ws_col).write(console)?;
+  }
+  Ok(())
+}
+
+/// Catch the signals meant for the container, and the resizes of our
+/// terminal with `tty`. Returns the read end of the pipe that the signals
+/// wake the relay up with.
+fn catch_signals(tty: bool) -> Result<RawFd> {
+  let (wake_read, wake_write) = pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK)?;
+  WAKE_UP.store(wake_write, Ordering::SeqCst);
+  let handler = SigAction::new(
+    SigHandler::Handler(handle_signal),
+    SaFlags::empty(),
+    SigSet::empty(),
+  );
+  for signal in FORWARDED_SIGNALS {
+    unsafe { sigaction(*signal, &handler)? };
+  }
+  if tty {
+    unsafe { sigaction(Signal::SIGWINCH, &handler)? };
+  }
+  Ok(wake_read)
+}
+
+/// Empty the wake up pipe, the pending signals say what happened.
+fn drain(wake_up: RawFd) {
+  let mut buf = [0; 64];
+  while let Ok(len) = read(wake_up, &mut buf) {
+    if len == 0 {
+      break;
+    }
+  }
+}
+
+/// Send the signals we caught to the init process of the container, and the
+/// size of our terminal once it got resized.
+fn send_signals(console: &mut UnixStream) -> Result<()> {
+  let pending = PENDING_SIGNALS.swap(0, Ordering::SeqCst);
+  if pending & (1 << Signal::SIGWINCH as i32) != 0 {
+    send_window_size(console)?;
+  }
+  for signal in FORWARDED_SIGNALS {
+    if pending & (1 << *signal as i32) != 0 {
+      Frame::Signal(*signal as i32).write(console)?;
+    }
+  }
+  Ok(())
+}
+
+/// Relay the console of a container to our terminal, until the user detaches
+/// or the container exits. Our input is only forwarded for interactive
+/// containers, and only searched for the detach keys with a terminal. The
+/// termination signals we get are forwarded to the container.
+pub fn relay(
+  mut console: UnixStream,
+  tty: bool,
+  interactive: bool,
+  mut detach_keys: DetachKeys,
+) -> Result<Disconnect> {
+  // Every key goes to the container as it is, including Ctrl-C.
+  let _raw_mode = if tty && interactive && isatty(STDIN).unwrap_or(false) {
+    Some(RawMode::enable()?)
+  } else {
+    None
+  };
+  let wake_up = catch_signals(tty)?;
+  if tty {
+    send_window_size(&mut console)?;
+  }
+
+  let mut stdin_open = interactive;
+  let mut buf = [0; BUFFER_SIZE];
+  loop {
+    send_signals(&mut console)?;
+
+    let mut fds = vec![
+      PollFd::new(console.as_raw_fd(), PollFlags::POLLIN),
+      PollFd::new(wake_up, PollFlags::POLLIN),
+    ];
+    if stdin_open {
+      fds.push(PollFd::new(STDIN, PollFlags::POLLIN));
+    }
+    match poll(&mut fds, -1) {
+      Ok(_) => {}
+      // Interrupted by a signal.
+      Err(nix::Error::Sys(Errno::EINTR)) => continue,
+      Err(err) => return Err(Box::new(err)),
+    }
+
+    if tty::is_ready(&fds[1]) {
+      drain(wake_up);
+    }
+    if tty::is_ready(&fds[0]) {
+      match Frame::read(&mut console)? {
+        Some(Frame::Stdout(data)) => tty::write_all(STDOUT, &data)?,
+        Some(Frame::Stderr(data)) => tty::write_all(STDERR, &data)?,
+        Some(Frame::Exit(code)) => return Ok(Disconnect::Exited(code)),
+        Some(_) => {}
+        None => return Err(Box::new(SupervisorError::Disconnected)),
+      }
+    }
+    if stdin_open && tty::is_ready(&fds[2]) {
+      match read(STDIN, &mut buf) {
+        Err(nix::Error::Sys(Errno::EINTR)) => {}
+        Ok(0) | Err(_) => {
+          stdin_open = false;
+          Frame::CloseStdin.write(&mut console)?;
+        }
+        Ok(len) if tty => {
+          let (input, detached) = detach_keys.scan(&buf[..len]);
+          if !input.is_empty() {
+            Frame::Stdin(input).write(&mut console)?;
+          }
+          if detached {
+            return Ok(Disconnect::Detached);
+          }
+        }
+        Ok(len) => Frame::Stdin(buf[..len].to_vec()).write(&mut console)?,
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_keys() {
+    assert_eq!(
+      DetachKeys::parse("ctrl-p,ctrl-q").unwrap().keys,
+      vec![0x10, 0x11]
+    );
+    assert_eq!(
+      DetachKeys::parse("ctrl-@, CTRL-_").unwrap().keys,
+      vec![0, 0x1f]
+    );
+    assert_eq!(DetachKeys::parse("a,ctrl-a").unwrap().keys, vec![b'a', 1]);
+  }
+
+  #[test]
+  fn parse_invalid_keys() {
+    for value in &["", "ctrl-p,", "ctrl-1", "ctrl-", "ctrl-pq", "ab", "é"] {
+      assert!(DetachKeys::parse(value).is_err(), "{}", value);
+    }
+  }
+
+  #[test]
+  fn scan_forwards_input() {
+    let mut keys = DetachKeys::parse("ctrl-p,ctrl-q").unwrap();
+    assert_eq!(keys.scan(b"ls\r"), (b"ls\r".to_vec(), false));
+    assert_eq!(keys.scan(b""), (vec![], false));
+  }
+
+  #[test]
+  fn scan_finds_sequence() {
+    let mut keys = DetachKeys::parse("ctrl-p,ctrl-q").unwrap();
+    assert_eq!(keys.scan(b"ab\x10\x11cd"), (b"ab".to_vec(), true));
+
+    // The sequence can be split over reads, the first key is held back.
+    let mut keys = DetachKeys::parse("ctrl-p,ctrl-q").unwrap();
+    assert_eq!(keys.scan(b"a\x10"), (b"a".to_vec(), false));
+    assert_eq!(keys.scan(b"\x11"), (vec![], true));
+  }
+
+  #[test]
+  fn scan_releases_partial_sequence() {
+    let mut keys = DetachKeys::parse("ctrl-p,ctrl-q").unwrap();
+    assert_eq!(keys.scan(b"\x10x"), (b"\x10x".to_vec(), false));
+    assert_eq!(keys.scan(b"\x10\x10\x11"), (b"\x10".to_vec(), true));
+
+    let mut keys = DetachKeys::parse("ctrl-p").unwrap();
+    assert_eq!(keys.scan(b"\x10\x10"), (vec![], true));
+  }
+}
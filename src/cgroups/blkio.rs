@@ -0,0 +1,78 @@
+use super::{parse_bytes, CgroupError};
+use crate::Result;
+use nix::sys::stat::{major, minor, stat, SFlag};
+use serde::{Deserialize, Serialize};
+
+/// A throttling limit of a single block device, either in bytes or in IO
+/// operations per second.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThrottleDevice {
+  pub path: String,
+  pub major: u64,
+  pub minor: u64,
+  pub rate: u64,
+}
+
+impl ThrottleDevice {
+  /// Parse a `<device path>:<rate>` pair like `/dev/sda:10mb`. Byte rates
+  /// accept the same units as the memory limits.
+  pub fn parse(value: &str, bytes: bool) -> Result<Self> {
+    let invalid = || CgroupError::InvalidValue(value.to_string());
+    let index = value.rfind(':').ok_or_else(invalid)?;
+    let (path, rate) = (&value[..index], &value[index + 1..]);
+
+    let rate = if bytes {
+      parse_bytes(rate)?
+    } else {
+      rate.parse().map_err(|_| invalid())?
+    };
+    if rate < 0 {
+      return Err(Box::new(invalid()));
+    }
+
+    let (major, minor) = device_number(path)?;
+    Ok(ThrottleDevice {
+      path: path.to_string(),
+      major,
+      minor,
+      rate: rate as u64,
+    })
+  }
+
+  /// The `major:minor` pair the cgroup files identify the device with.
+  pub fn device(&self) -> String {
+    format!("{}:{}", self.major, self.minor)
+  }
+}
+
+/// Resolve the path of a block device into its major and minor numbers.
+fn device_number(path: &str) -> Result<(u64, u64)> {
+  let stat = stat(path)?;
+  if SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT != SFlag::S_IFBLK {
+    return Err(Box::new(CgroupError::InvalidValue(format!(
+      "{} is not a block device",
+      path
+    ))));
+  }
+  Ok((major(stat.st_rdev), minor(stat.st_rdev)))
+}
+
+/// Parse every value of a repeated throttling option.
+pub fn parse_arg(args: &clap::ArgMatches, name: &str, bytes: bool) -> Result<Vec<ThrottleDevice>> {
+  let mut devices = vec![];
+  if let Some(values) = args.values_of(name) {
+    for value in values {
+      devices.push(ThrottleDevice::parse(value, bytes)?);
+    }
+  }
+  Ok(devices)
+}
+
+/// Merge the limits given to the `update` subcommand into the current ones.
+/// A new limit of a device replaces its previous one.
+pub fn merge(devices: &mut Vec<ThrottleDevice>, changes: &[ThrottleDevice]) {
+  for change in changes {
+    devices.retain(|device| device.device() != change.device());
+    devices.push(change.clone());
+  }
+}
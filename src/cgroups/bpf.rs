@@ -0,0 +1,188 @@
+use crate::devices::{DeviceRule, DeviceType};
+use crate::Result;
+use nix::fcntl::{open, OFlag};
+use nix::libc;
+use nix::sys::stat::Mode;
+use nix::unistd::close;
+use std::mem;
+use std::path::Path;
+
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_PROG_ATTACH: libc::c_long = 8;
+const BPF_PROG_TYPE_CGROUP_DEVICE: u32 = 15;
+const BPF_CGROUP_DEVICE: u32 = 6;
+
+// Values of `bpf_cgroup_dev_ctx.access_type`. The lower 16 bits hold the
+// device type and the upper 16 bits the access.
+const BPF_DEVCG_DEV_BLOCK: i32 = 1;
+const BPF_DEVCG_DEV_CHAR: i32 = 2;
+const BPF_DEVCG_ACC_MKNOD: i32 = 1;
+const BPF_DEVCG_ACC_READ: i32 = 2;
+const BPF_DEVCG_ACC_WRITE: i32 = 4;
+
+// Instruction opcodes we need.
+const LDX_MEM_W: u8 = 0x61;
+const ALU64_AND_K: u8 = 0x57;
+const ALU64_RSH_K: u8 = 0x77;
+const ALU64_MOV_K: u8 = 0xb7;
+const ALU64_MOV_X: u8 = 0xbf;
+const JMP_JNE_K: u8 = 0x55;
+const JMP_EXIT: u8 = 0x95;
+
+// Registers. R1 holds the context when the program starts.
+const R0: u8 = 0;
+const R1: u8 = 1;
+const R2: u8 = 2;
+const R3: u8 = 3;
+const R4: u8 = 4;
+const R5: u8 = 5;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Instruction {
+  code: u8,
+  /// Destination register in the lower 4 bits, source in the upper 4 bits.
+  regs: u8,
+  off: i16,
+  imm: i32,
+}
+
+impl Instruction {
+  fn new(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Self {
+    Instruction {
+      code,
+      regs: (src << 4) | dst,
+      off,
+      imm,
+    }
+  }
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ProgLoadAttr {
+  prog_type: u32,
+  insn_cnt: u32,
+  insns: u64,
+  license: u64,
+  log_level: u32,
+  log_size: u32,
+  log_buf: u64,
+  kern_version: u32,
+  prog_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ProgAttachAttr {
+  target_fd: u32,
+  attach_bpf_fd: u32,
+  attach_type: u32,
+  attach_flags: u32,
+}
+
+/// Build a program that allows the accesses matching any of the rules, and
+/// denies everything else.
+fn program(rules: &[DeviceRule]) -> Vec<Instruction> {
+  let mut insns = vec![
+    // R2 = device type, R3 = access, R4 = major, R5 = minor.
+    Instruction::new(LDX_MEM_W, R2, R1, 0, 0),
+    Instruction::new(ALU64_AND_K, R2, 0, 0, 0xffff),
+    Instruction::new(LDX_MEM_W, R3, R1, 0, 0),
+    Instruction::new(ALU64_RSH_K, R3, 0, 0, 16),
+    Instruction::new(LDX_MEM_W, R4, R1, 4, 0),
+    Instruction::new(LDX_MEM_W, R5, R1, 8, 0),
+  ];
+
+  for rule in rules {
+    // Every check jumps over the rest of the block to the next rule when it
+    // doesn't match. The offsets are fixed up once the block is complete.
+    let mut block = vec![];
+    match rule.kind {
+      DeviceType::Char => block.push(Instruction::new(JMP_JNE_K, R2, 0, 0, BPF_DEVCG_DEV_CHAR)),
+      DeviceType::Block => block.push(Instruction::new(JMP_JNE_K, R2, 0, 0, BPF_DEVCG_DEV_BLOCK)),
+      DeviceType::All => {}
+    }
+    let access = access_mask(&rule.access);
+    if access != BPF_DEVCG_ACC_MKNOD | BPF_DEVCG_ACC_READ | BPF_DEVCG_ACC_WRITE {
+      // Skip the rule if any of the requested accesses is not allowed.
+      block.push(Instruction::new(ALU64_MOV_X, R1, R3, 0, 0));
+      block.push(Instruction::new(ALU64_AND_K, R1, 0, 0, !access));
+      block.push(Instruction::new(JMP_JNE_K, R1, 0, 0, 0));
+    }
+    if let Some(major) = rule.major {
+      block.push(Instruction::new(JMP_JNE_K, R4, 0, 0, major as i32));
+    }
+    if let Some(minor) = rule.minor {
+      block.push(Instruction::new(JMP_JNE_K, R5, 0, 0, minor as i32));
+    }
+    block.push(Instruction::new(ALU64_MOV_K, R0, 0, 0, 1));
+    block.push(Instruction::new(JMP_EXIT, 0, 0, 0, 0));
+
+    let len = block.len();
+    for (index, insn) in block.iter_mut().enumerate() {
+      if insn.code == JMP_JNE_K {
+        insn.off = (len - index - 1) as i16;
+      }
+    }
+    insns.extend(block);
+  }
+
+  // Nothing matched, deny the access.
+  insns.push(Instruction::new(ALU64_MOV_K, R0, 0, 0, 0));
+  insns.push(Instruction::new(JMP_EXIT, 0, 0, 0, 0));
+  insns
+}
+
+fn access_mask(access: &str) -> i32 {
+  access.chars().fold(0, |mask, c| match c {
+    'm' => mask | BPF_DEVCG_ACC_MKNOD,
+    'r' => mask | BPF_DEVCG_ACC_READ,
+    'w' => mask | BPF_DEVCG_ACC_WRITE,
+    _ => mask,
+  })
+}
+
+fn bpf<T>(cmd: libc::c_long, attr: &T) -> Result<i32> {
+  let ret = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr as *const T, mem::size_of::<T>()) };
+  if ret < 0 {
+    return Err(Box::new(std::io::Error::last_os_error()));
+  }
+  Ok(ret as i32)
+}
+
+/// The v2 hierarchy has no `devices` controller. Instead, load a program for
+/// the rules that the kernel runs on every device access, and attach it to
+/// the cgroup at the given path. The attachment keeps the program alive after
+/// we close it.
+pub fn attach_device_filter(cgroup: &Path, rules: &[DeviceRule]) -> Result<()> {
+  let insns = program(rules);
+  let license = b"MIT\0";
+  let load = ProgLoadAttr {
+    prog_type: BPF_PROG_TYPE_CGROUP_DEVICE,
+    insn_cnt: insns.len() as u32,
+    insns: insns.as_ptr() as u64,
+    license: license.as_ptr() as u64,
+    ..Default::default()
+  };
+  let prog_fd = bpf(BPF_PROG_LOAD, &load)?;
+
+  let cgroup_fd = match open(cgroup, OFlag::O_RDONLY | OFlag::O_DIRECTORY, Mode::empty()) {
+    Ok(fd) => fd,
+    Err(err) => {
+      let _ = close(prog_fd);
+      return Err(Box::new(err));
+    }
+  };
+  let attach = ProgAttachAttr {
+    target_fd: cgroup_fd as u32,
+    attach_bpf_fd: prog_fd as u32,
+    attach_type: BPF_CGROUP_DEVICE,
+    ..Default::default()
+  };
+  let result = bpf(BPF_PROG_ATTACH, &attach);
+
+  let _ = close(cgroup_fd);
+  let _ = close(prog_fd);
+  result.map(|_| ())
+}
@@ -1,46 +1,569 @@
+mod blkio;
+mod bpf;
+mod oom;
+mod stats;
+mod topology;
+mod v1;
+mod v2;
+
+use crate::devices::DeviceRule;
+use crate::Result;
+use nix::libc;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
-use std::process;
-
-/// Initialize the cgroups inside the container process.
-pub fn init(args: &clap::ArgMatches) {
-  let mut changed_cgroup_val = false;
-  let mut cgroups = PathBuf::from("/sys/fs/cgroup/");
-  assert!(cgroups.exists(), "Failed to locate cgroups");
-  cgroups.push("pids");
-  assert!(cgroups.exists(), "Failed to locate pids");
-  cgroups.push("canova_test");
-
-  if cgroups.exists() {
-    // Shouldn't happen
-    warn!("Unexecped existing cgroup");
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub use self::blkio::ThrottleDevice;
+pub use self::oom::{OomEvents, OomWatcher};
+pub use self::stats::{CpuStats, IoStats, MemoryStats, PidsStats, Pressure, PressureStats, Stats};
+pub use self::v1::CgroupV1;
+pub use self::v2::CgroupV2;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// Every cgroup we create lives under this parent group, so they don't mix
+/// with the groups of the other tools running on the host.
+const CGROUP_PARENT: &str = "container-rs";
+/// How many times we try to remove a cgroup before giving up. Killed tasks
+/// might take a moment to leave the group.
+const REMOVE_ATTEMPTS: u64 = 5;
+/// How long we wait for the freezer to confirm the new state.
+const FREEZE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Default CFS period in microseconds, used by `--cpus`.
+const DEFAULT_CPU_PERIOD: u64 = 100_000;
+
+#[derive(Debug)]
+pub enum CgroupError {
+  NotMounted,
+  ControllerNotAvailable(String),
+  Write(PathBuf, std::io::Error),
+  BelowUsage(PathBuf),
+  InvalidValue(String),
+  InvalidResources(&'static str),
+  FreezeTimeout,
+}
+
+impl fmt::Display for CgroupError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      CgroupError::NotMounted => write!(f, "Failed to locate cgroups at {}", CGROUP_ROOT),
+      CgroupError::ControllerNotAvailable(controller) => {
+        write!(f, "The {} cgroup controller is not available", controller)
+      }
+      CgroupError::Write(path, err) => write!(f, "Failed to write {:?}: {}", path, err),
+      CgroupError::BelowUsage(path) => write!(
+        f,
+        "The kernel refused the new limit of {:?}, it's below the current usage",
+        path
+      ),
+      CgroupError::InvalidValue(value) => write!(f, "Invalid resource value: {}", value),
+      CgroupError::InvalidResources(reason) => write!(f, "Invalid resources: {}", reason),
+      CgroupError::FreezeTimeout => write!(f, "Timed out waiting for the freezer"),
+    }
+  }
+}
+
+impl Error for CgroupError {}
+
+/// Resource limits of a container. `None` leaves the kernel default in place.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Resources {
+  /// Maximum number of processes, `-1` for unlimited.
+  pub pids_max: Option<i64>,
+  /// Memory limit in bytes.
+  pub memory: Option<i64>,
+  /// Memory plus swap limit in bytes, `-1` for unlimited swap.
+  pub memory_swap: Option<i64>,
+  /// Memory soft limit in bytes.
+  pub memory_reservation: Option<i64>,
+  /// Don't let the OOM killer kill the container processes.
+  #[serde(default)]
+  pub oom_kill_disable: bool,
+  /// Relative CPU weight, in the v1 `cpu.shares` range.
+  pub cpu_shares: Option<u64>,
+  /// CPU time the container can use in each period, in microseconds. `-1`
+  /// for unlimited.
+  pub cpu_quota: Option<i64>,
+  /// Length of the CFS period in microseconds.
+  pub cpu_period: Option<u64>,
+  /// CPUs the container can run on, e.g. `0-3,6`.
+  pub cpuset_cpus: Option<String>,
+  /// Memory nodes the container can allocate from.
+  pub cpuset_mems: Option<String>,
+  /// Relative block IO weight, between 10 and 1000.
+  pub blkio_weight: Option<u64>,
+  /// Read rate limits in bytes per second.
+  #[serde(default)]
+  pub device_read_bps: Vec<ThrottleDevice>,
+  /// Write rate limits in bytes per second.
+  #[serde(default)]
+  pub device_write_bps: Vec<ThrottleDevice>,
+  /// Read rate limits in IO operations per second.
+  #[serde(default)]
+  pub device_read_iops: Vec<ThrottleDevice>,
+  /// Write rate limits in IO operations per second.
+  #[serde(default)]
+  pub device_write_iops: Vec<ThrottleDevice>,
+}
+
+impl Resources {
+  /// Collect the resource limits from the `run` subcommand arguments.
+  pub fn from_args(args: &clap::ArgMatches) -> Result<Self> {
+    let resources = Self::parse(args)?;
+    resources.validate()?;
+    Ok(resources)
+  }
+
+  /// Apply the limits given to the `update` subcommand on top of the current
+  /// ones. The limits that are not given stay the same. Returns the limits to
+  /// write to the cgroup, the given ones and those written together with them.
+  pub fn update(&mut self, args: &clap::ArgMatches) -> Result<Self> {
+    let mut changes = Self::parse(args)?;
+    self.pids_max = changes.pids_max.or(self.pids_max);
+    self.memory = changes.memory.or(self.memory);
+    self.memory_swap = changes.memory_swap.or(self.memory_swap);
+    self.memory_reservation = changes.memory_reservation.or(self.memory_reservation);
+    self.cpu_shares = changes.cpu_shares.or(self.cpu_shares);
+    self.cpu_quota = changes.cpu_quota.or(self.cpu_quota);
+    self.cpu_period = changes.cpu_period.or(self.cpu_period);
+    if changes.cpuset_cpus.is_some() {
+      self.cpuset_cpus = changes.cpuset_cpus.clone();
+    }
+    if changes.cpuset_mems.is_some() {
+      self.cpuset_mems = changes.cpuset_mems.clone();
+    }
+    self.blkio_weight = changes.blkio_weight.or(self.blkio_weight);
+    self.oom_kill_disable |= changes.oom_kill_disable;
+    blkio::merge(&mut self.device_read_bps, &changes.device_read_bps);
+    blkio::merge(&mut self.device_write_bps, &changes.device_write_bps);
+    blkio::merge(&mut self.device_read_iops, &changes.device_read_iops);
+    blkio::merge(&mut self.device_write_iops, &changes.device_write_iops);
+    self.validate()?;
+
+    // The swap limit is written relative to the memory limit, and v2 writes
+    // the CPU quota and period together.
+    if changes.memory.is_some() || changes.memory_swap.is_some() {
+      changes.memory = self.memory;
+      changes.memory_swap = self.memory_swap;
+    }
+    if changes.cpu_quota.is_some() || changes.cpu_period.is_some() {
+      changes.cpu_quota = self.cpu_quota;
+      changes.cpu_period = self.cpu_period;
+    }
+    Ok(changes)
+  }
+
+  fn parse(args: &clap::ArgMatches) -> Result<Self> {
+    let pids_max = match args.value_of("pids.max") {
+      Some(pids_max) => Some(pids_max.parse()?),
+      None => None,
+    };
+
+    let mut resources = Resources {
+      pids_max,
+      memory: parse_bytes_arg(args, "memory")?,
+      memory_swap: parse_bytes_arg(args, "memory-swap")?,
+      memory_reservation: parse_bytes_arg(args, "memory-reservation")?,
+      oom_kill_disable: args.is_present("oom-kill-disable"),
+      cpu_shares: parse_arg(args, "cpu-shares")?,
+      cpu_quota: parse_arg(args, "cpu-quota")?,
+      cpu_period: parse_arg(args, "cpu-period")?,
+      cpuset_cpus: args.value_of("cpuset-cpus").map(String::from),
+      cpuset_mems: args.value_of("cpuset-mems").map(String::from),
+      blkio_weight: parse_arg(args, "blkio-weight")?,
+      device_read_bps: blkio::parse_arg(args, "device-read-bps", true)?,
+      device_write_bps: blkio::parse_arg(args, "device-write-bps", true)?,
+      device_read_iops: blkio::parse_arg(args, "device-read-iops", false)?,
+      device_write_iops: blkio::parse_arg(args, "device-write-iops", false)?,
+    };
+    if let Some(cpus) = args.value_of("cpus") {
+      resources.set_cpus(cpus.parse()?)?;
+    }
+    Ok(resources)
+  }
+
+  /// Limit the container to the given number of CPUs by turning it into a
+  /// quota over the CFS period.
+  fn set_cpus(&mut self, cpus: f64) -> Result<()> {
+    if self.cpu_quota.is_some() {
+      return Err(Box::new(CgroupError::InvalidResources(
+        "--cpus and --cpu-quota can't be used together",
+      )));
+    }
+    if cpus <= 0.0 {
+      return Err(Box::new(CgroupError::InvalidResources(
+        "--cpus should be greater than zero",
+      )));
+    }
+
+    let period = *self.cpu_period.get_or_insert(DEFAULT_CPU_PERIOD);
+    self.cpu_quota = Some((cpus * period as f64) as i64);
+    Ok(())
+  }
+
+  fn validate(&self) -> Result<()> {
+    if let Some(memory_swap) = self.memory_swap {
+      match self.memory {
+        None => {
+          return Err(Box::new(CgroupError::InvalidResources(
+            "--memory-swap requires --memory to be set",
+          )))
+        }
+        Some(memory) if memory_swap >= 0 && memory_swap < memory => {
+          return Err(Box::new(CgroupError::InvalidResources(
+            "--memory-swap should be greater than or equal to --memory",
+          )))
+        }
+        _ => {}
+      }
+    }
+
+    if let (Some(memory), Some(reservation)) = (self.memory, self.memory_reservation) {
+      if reservation > memory {
+        return Err(Box::new(CgroupError::InvalidResources(
+          "--memory-reservation should be smaller than --memory",
+        )));
+      }
+    }
+
+    if let Some(weight) = self.blkio_weight {
+      if !(10..=1000).contains(&weight) {
+        return Err(Box::new(CgroupError::InvalidResources(
+          "--blkio-weight should be between 10 and 1000",
+        )));
+      }
+    }
+
+    self.validate_cpu()
+  }
+
+  /// Check the CPU limits against the topology of the host.
+  fn validate_cpu(&self) -> Result<()> {
+    if let Some(shares) = self.cpu_shares {
+      if !(2..=262_144).contains(&shares) {
+        return Err(Box::new(CgroupError::InvalidResources(
+          "--cpu-shares should be between 2 and 262144",
+        )));
+      }
+    }
+    if let Some(period) = self.cpu_period {
+      if !(1000..=1_000_000).contains(&period) {
+        return Err(Box::new(CgroupError::InvalidResources(
+          "--cpu-period should be between 1ms and 1s",
+        )));
+      }
+    }
+
+    let online_cpus = topology::online_cpus()?;
+    if let Some(quota) = self.cpu_quota {
+      if (0..1000).contains(&quota) {
+        return Err(Box::new(CgroupError::InvalidResources(
+          "--cpu-quota should be at least 1ms",
+        )));
+      }
+      let period = self.cpu_period.unwrap_or(DEFAULT_CPU_PERIOD) as i64;
+      if quota > period * online_cpus.len() as i64 {
+        return Err(Box::new(CgroupError::InvalidResources(
+          "the CPU quota is more than the number of CPUs on the host",
+        )));
+      }
+    }
+    if let Some(cpus) = &self.cpuset_cpus {
+      topology::validate_list(
+        cpus,
+        &online_cpus,
+        "--cpuset-cpus contains CPUs that are not online on the host",
+      )?;
+    }
+    if let Some(mems) = &self.cpuset_mems {
+      topology::validate_list(
+        mems,
+        &topology::online_nodes()?,
+        "--cpuset-mems contains memory nodes that are not online on the host",
+      )?;
+    }
+    Ok(())
+  }
+
+  /// The CPU weight for the v2 `cpu.weight` file, mapped from the shares.
+  fn cpu_weight(&self) -> Option<u64> {
+    self
+      .cpu_shares
+      .map(|shares| 1 + ((shares - 2) * 9999) / 262_142)
+  }
+
+  /// The IO weight for the v2 `io.weight` file, mapped from the blkio weight.
+  fn io_weight(&self) -> Option<u64> {
+    self
+      .blkio_weight
+      .map(|weight| 1 + ((weight - 10) * 9999) / 990)
+  }
+}
+
+/// Parse a size like `512m` or `2g` into bytes. `-1` is kept as is, it means
+/// no limit.
+pub fn parse_bytes(value: &str) -> Result<i64> {
+  if value == "-1" {
+    return Ok(-1);
+  }
+
+  let value = value.to_lowercase();
+  let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+    Some(index) => value.split_at(index),
+    None => (value.as_str(), ""),
+  };
+  let multiplier: i64 = match unit.strip_suffix('b').unwrap_or(unit) {
+    "" => 1,
+    "k" => 1 << 10,
+    "m" => 1 << 20,
+    "g" => 1 << 30,
+    "t" => 1 << 40,
+    _ => return Err(Box::new(CgroupError::InvalidValue(value.clone()))),
+  };
+  let number: i64 = number
+    .parse()
+    .map_err(|_| CgroupError::InvalidValue(value.clone()))?;
+  number
+    .checked_mul(multiplier)
+    .ok_or_else(|| Box::new(CgroupError::InvalidValue(value.clone())) as _)
+}
+
+fn parse_arg<T>(args: &clap::ArgMatches, name: &str) -> Result<Option<T>>
+where
+  T: std::str::FromStr,
+  T::Err: Error + 'static,
+{
+  match args.value_of(name) {
+    Some(value) => Ok(Some(value.parse()?)),
+    None => Ok(None),
+  }
+}
+
+fn parse_bytes_arg(args: &clap::ArgMatches, name: &str) -> Result<Option<i64>> {
+  match args.value_of(name) {
+    Some(value) => Ok(Some(parse_bytes(value)?)),
+    None => Ok(None),
+  }
+}
+
+/// A cgroup of a single container. Both the legacy v1 hierarchies and the v2
+/// unified hierarchy are implemented behind this trait.
+pub trait Cgroup {
+  /// Create the cgroup on the host.
+  fn create(&self) -> Result<()>;
+  /// Write the resource limits into the controller files.
+  fn apply(&self, resources: &Resources) -> Result<()>;
+  /// Deny access to every device except the ones the rules allow.
+  fn apply_devices(&self, rules: &[DeviceRule]) -> Result<()>;
+  /// Move the process into the cgroup.
+  fn add_task(&self, pid: Pid) -> Result<()>;
+  /// Processes that are currently inside the cgroup.
+  fn tasks(&self) -> Result<Vec<Pid>>;
+  /// How many processes of the cgroup got killed by the OOM killer.
+  fn oom_kills(&self) -> Result<u64>;
+  /// Get notified about the memory events of the cgroup, like OOM kills.
+  fn oom_events(&self) -> Result<OomEvents>;
+  /// Collect the current resource usage of the cgroup.
+  fn stats(&self) -> Result<Stats>;
+  /// Stop every process of the cgroup, and wait until they are all frozen.
+  fn freeze(&self) -> Result<()>;
+  /// Let the frozen processes of the cgroup continue.
+  fn thaw(&self) -> Result<()>;
+  /// Where the cgroup lives on the host, a group in every hierarchy.
+  fn paths(&self) -> Vec<PathBuf>;
+  /// Remove the cgroup from the host.
+  fn remove(&self) -> Result<()>;
+}
+
+/// Whether the host mounts the v2 unified hierarchy at the cgroup root.
+pub fn is_unified() -> bool {
+  Path::new(CGROUP_ROOT).join("cgroup.controllers").exists()
+}
+
+/// Create the cgroup handle for the given name, picking the backend that
+/// matches the hierarchy of the host.
+pub fn new(name: &str) -> Result<Box<dyn Cgroup>> {
+  if !Path::new(CGROUP_ROOT).exists() {
+    return Err(Box::new(CgroupError::NotMounted));
+  }
+
+  if is_unified() {
+    info!("Using the cgroup v2 unified hierarchy");
+    Ok(Box::new(CgroupV2::new(name)))
+  } else {
+    info!("Using the cgroup v1 hierarchies");
+    Ok(Box::new(CgroupV1::new(name)))
+  }
+}
+
+/// Names of the cgroups we created on the host, including the ones left
+/// behind by containers that are gone.
+pub fn list() -> Result<Vec<String>> {
+  if is_unified() {
+    v2::list()
   } else {
-    fs::create_dir(&cgroups).expect("Failed to create the cgroup");
+    v1::list()
+  }
+}
+
+/// Create the cgroup of a container, apply its limits and move its init
+/// process into it.
+pub fn setup(
+  cgroup: &dyn Cgroup,
+  resources: &Resources,
+  devices: &[DeviceRule],
+  pid: Pid,
+) -> Result<()> {
+  cgroup.create()?;
+  cgroup.apply(resources)?;
+  cgroup.apply_devices(devices)?;
+  cgroup.add_task(pid)
+}
+
+/// Remove the cgroup, killing whatever is still running inside it first.
+pub fn destroy(cgroup: &dyn Cgroup) -> Result<()> {
+  for attempt in 1..=REMOVE_ATTEMPTS {
+    for pid in cgroup.tasks()? {
+      warn!("Killing the leftover process {} of the cgroup", pid);
+      let _ = kill(pid, Signal::SIGKILL);
+    }
+    // Frozen processes can't handle the signal until they are thawed.
+    if let Err(err) = cgroup.thaw() {
+      warn!("Failed to thaw the cgroup: {}", err);
+    }
+
+    match cgroup.remove() {
+      Ok(()) => return Ok(()),
+      Err(err) if attempt == REMOVE_ATTEMPTS => return Err(err),
+      Err(err) => {
+        warn!("Failed to remove the cgroup, retrying: {}", err);
+        thread::sleep(Duration::from_millis(100 * attempt));
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Poll until the check passes. Used to wait for the freezer, which changes
+/// its state asynchronously.
+fn wait_for<F>(mut check: F) -> Result<()>
+where
+  F: FnMut() -> Result<bool>,
+{
+  let start = Instant::now();
+  while !check()? {
+    if start.elapsed() > FREEZE_TIMEOUT {
+      return Err(Box::new(CgroupError::FreezeTimeout));
+    }
+    thread::sleep(Duration::from_millis(10));
+  }
+  Ok(())
+}
+
+/// Read the pids listed in a `cgroup.procs` file.
+fn read_tasks(path: &Path) -> Result<Vec<Pid>> {
+  if !path.exists() {
+    return Ok(vec![]);
+  }
+
+  let mut tasks = vec![];
+  for line in fs::read_to_string(path)?.lines() {
+    tasks.push(Pid::from_raw(line.trim().parse()?));
+  }
+  Ok(tasks)
+}
+
+/// Names of the groups inside the given directory.
+fn read_groups(path: &Path) -> Result<Vec<String>> {
+  if !path.exists() {
+    return Ok(vec![]);
+  }
+
+  let mut groups = vec![];
+  for entry in fs::read_dir(path)? {
+    let entry = entry?;
+    if entry.file_type()?.is_dir() {
+      groups.push(entry.file_name().into_string().unwrap());
+    }
+  }
+  Ok(groups)
+}
+
+/// Read a single key out of a flat keyed file like `memory.events`.
+fn read_key(path: &Path, key: &str) -> Result<u64> {
+  if !path.exists() {
+    return Ok(0);
   }
 
-  if let Some(max_pids) = args.value_of("pids.max") {
-    if max_pids != "-1" {
-      // If it's -1, just ignore the limit.
-      changed_cgroup_val = true;
-      let pids_max = cgroups.join("pids.max");
-      fs::write(pids_max, max_pids.as_bytes()).expect("Failed to write the pids.max");
+  for line in fs::read_to_string(path)?.lines() {
+    let mut parts = line.split_whitespace();
+    if parts.next() == Some(key) {
+      return Ok(parts.next().unwrap_or("0").parse()?);
     }
   }
+  Ok(0)
+}
+
+/// Write a single value into a cgroup file.
+fn write(path: &Path, value: &str) -> Result<()> {
+  fs::write(path, value.as_bytes())
+    .map_err(|err| Box::new(CgroupError::Write(path.to_path_buf(), err)) as _)
+}
 
-  if changed_cgroup_val {
-    let cgroup_procs = cgroups.join("cgroup.procs");
-    fs::write(cgroup_procs, process::id().to_string().as_bytes())
-      .expect("Failed to attach the process to the cgroup");
+/// Write a memory limit into a cgroup file. The kernel answers with EBUSY
+/// when it can't bring the usage down to a lowered limit.
+fn write_memory_limit(path: &Path, value: &str) -> Result<()> {
+  fs::write(path, value.as_bytes()).map_err(|err| {
+    let err = if err.raw_os_error() == Some(libc::EBUSY) {
+      CgroupError::BelowUsage(path.to_path_buf())
+    } else {
+      CgroupError::Write(path.to_path_buf(), err)
+    };
+    Box::new(err) as _
+  })
+}
+
+/// Format a limit the way the cgroup files expect it, `-1` means no limit.
+fn limit_value(value: i64) -> String {
+  if value < 0 {
+    "max".to_string()
+  } else {
+    value.to_string()
   }
 }
 
-/// Deinitialize the cgroups after container process being destroyed.
-pub fn deinit() {
-  let mut cgroups = PathBuf::from("/sys/fs/cgroup/");
-  assert!(cgroups.exists(), "Failed to locate cgroups");
-  cgroups.push("pids");
-  assert!(cgroups.exists(), "Failed to locate pids");
-  cgroups.push("canova_test");
-  info!("cleaning up cgroups: {:?}", cgroups);
-  fs::remove_dir(cgroups).expect("Failed to remove the cgroup");
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_bytes_units() {
+    assert_eq!(parse_bytes("0").unwrap(), 0);
+    assert_eq!(parse_bytes("512").unwrap(), 512);
+    assert_eq!(parse_bytes("1k").unwrap(), 1024);
+    assert_eq!(parse_bytes("512M").unwrap(), 512 << 20);
+    assert_eq!(parse_bytes("2gb").unwrap(), 2 << 30);
+    assert_eq!(parse_bytes("1t").unwrap(), 1 << 40);
+    assert_eq!(parse_bytes("-1").unwrap(), -1);
+  }
+
+  #[test]
+  fn parse_bytes_invalid() {
+    for value in &["", "m", "-2", "-1m", "1.5g", "1p", "1bb", "1kbb", " 1k"] {
+      assert!(parse_bytes(value).is_err(), "{}", value);
+    }
+  }
+
+  #[test]
+  fn parse_bytes_overflow() {
+    assert_eq!(parse_bytes("9223372036854775807").unwrap(), i64::MAX);
+    assert!(parse_bytes("9223372036854775808").is_err());
+    assert_eq!(parse_bytes("8388607t").unwrap(), 8388607 << 40);
+    assert!(parse_bytes("8388608t").is_err());
+  }
 }
@@ -0,0 +1,124 @@
+use super::{read_key, Cgroup};
+use crate::Result;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::unistd::{close, read};
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// How long the watcher waits for an event before it checks whether the
+/// container exited, in milliseconds.
+const POLL_TIMEOUT: i32 = 100;
+
+/// The memory events of a cgroup. The file descriptor becomes readable when
+/// the memory controller reports an event, and the counter file holds the
+/// number of OOM kills.
+pub struct OomEvents {
+  fd: RawFd,
+  counter: PathBuf,
+}
+
+impl OomEvents {
+  pub(super) fn new(fd: RawFd, counter: PathBuf) -> Self {
+    OomEvents { fd, counter }
+  }
+
+  /// Wait for the next events, and consume them. Returns whether any came
+  /// before the timeout.
+  fn wait(&self, timeout: i32) -> Result<bool> {
+    let mut fds = [PollFd::new(self.fd, PollFlags::POLLIN)];
+    match poll(&mut fds, timeout) {
+      Ok(0) | Err(nix::Error::Sys(Errno::EINTR)) => return Ok(false),
+      Ok(_) => {}
+      Err(err) => return Err(Box::new(err)),
+    }
+    // Both eventfds and inotify descriptors are drained by reading them
+    // until they would block.
+    let mut buf = [0; 4096];
+    loop {
+      match read(self.fd, &mut buf) {
+        Ok(0) | Err(nix::Error::Sys(Errno::EAGAIN)) => return Ok(true),
+        Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => {}
+        Err(err) => return Err(Box::new(err)),
+      }
+    }
+  }
+
+  fn oom_kills(&self) -> Result<u64> {
+    read_key(&self.counter, "oom_kill")
+  }
+}
+
+impl Drop for OomEvents {
+  fn drop(&mut self) {
+    let _ = close(self.fd);
+  }
+}
+
+/// Watches the cgroup of a running container for OOM kills, and reports
+/// them as they happen.
+pub struct OomWatcher {
+  exited: Arc<AtomicBool>,
+  thread: JoinHandle<bool>,
+}
+
+impl OomWatcher {
+  /// Start watching the cgroup, if its memory controller reports events.
+  pub fn start(cgroup: &dyn Cgroup) -> Option<Self> {
+    let events = match cgroup.oom_events() {
+      Ok(events) => events,
+      Err(err) => {
+        warn!("Failed to watch the container for OOM kills: {}", err);
+        return None;
+      }
+    };
+    let exited = Arc::new(AtomicBool::new(false));
+    let watcher_exited = Arc::clone(&exited);
+    let thread = thread::spawn(move || watch(&events, &watcher_exited));
+    Some(OomWatcher { exited, thread })
+  }
+
+  /// Stop watching once the container exited. Returns whether the OOM killer
+  /// killed any process of the container meanwhile.
+  pub fn stop(self) -> bool {
+    self.exited.store(true, Ordering::SeqCst);
+    self.thread.join().unwrap_or(false)
+  }
+}
+
+fn watch(events: &OomEvents, exited: &AtomicBool) -> bool {
+  let mut oom_kills = 0;
+  // v1 signals the OOM before the kill is counted, the counter is checked
+  // again once the events calm down.
+  let mut pending = false;
+  while !exited.load(Ordering::SeqCst) {
+    match events.wait(POLL_TIMEOUT) {
+      Ok(true) => pending = true,
+      Ok(false) if pending => pending = false,
+      Ok(false) => continue,
+      Err(err) => {
+        warn!(
+          "Failed to wait for the memory events of the container: {}",
+          err
+        );
+        break;
+      }
+    }
+    // Other memory events wake us up too, like reaching the high limit.
+    match events.oom_kills() {
+      Ok(count) if count > oom_kills => {
+        warn!(
+          "The OOM killer killed {} process(es) of the container",
+          count - oom_kills
+        );
+        oom_kills = count;
+      }
+      Ok(_) => debug!("The container reported a memory event"),
+      Err(err) => warn!("Failed to read the OOM kills of the container: {}", err),
+    }
+  }
+  oom_kills > 0
+}
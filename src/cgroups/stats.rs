@@ -0,0 +1,113 @@
+use crate::Result;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+/// A snapshot of the resource usage of a cgroup. The counters are
+/// cumulative, rates like the CPU percentage are computed from two snapshots.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Stats {
+  pub cpu: CpuStats,
+  pub memory: MemoryStats,
+  pub pids: PidsStats,
+  pub io: IoStats,
+  /// Pressure stall information, only available with cgroup v2 on kernels
+  /// built with PSI.
+  pub pressure: Option<PressureStats>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CpuStats {
+  /// Total CPU time in microseconds.
+  pub usage_usec: u64,
+  pub user_usec: u64,
+  pub system_usec: u64,
+  /// Number of CFS periods that had a quota, and how many of them ran out.
+  pub nr_periods: u64,
+  pub nr_throttled: u64,
+  pub throttled_usec: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MemoryStats {
+  pub usage: u64,
+  /// `None` if the memory is not limited.
+  pub limit: Option<u64>,
+  /// Page cache that is charged to the cgroup.
+  pub cache: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PidsStats {
+  pub current: u64,
+  /// `None` if the number of processes is not limited.
+  pub limit: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct IoStats {
+  pub read_bytes: u64,
+  pub write_bytes: u64,
+  pub read_ops: u64,
+  pub write_ops: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PressureStats {
+  pub cpu: Option<Pressure>,
+  pub memory: Option<Pressure>,
+  pub io: Option<Pressure>,
+}
+
+/// The `some` line of a pressure file: the share of time at least one task
+/// was stalled, averaged over 10, 60 and 300 seconds, and the total stall
+/// time in microseconds.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Pressure {
+  pub avg10: f64,
+  pub avg60: f64,
+  pub avg300: f64,
+  pub total: u64,
+}
+
+impl Pressure {
+  /// Read the `some` line of a pressure file, if the file exists.
+  pub fn read(path: &Path) -> Result<Option<Self>> {
+    if !path.exists() {
+      return Ok(None);
+    }
+
+    let content = fs::read_to_string(path)?;
+    let line = match content.lines().find(|line| line.starts_with("some ")) {
+      Some(line) => line,
+      None => return Ok(None),
+    };
+
+    let mut pressure = Pressure::default();
+    for field in line.split_whitespace().skip(1) {
+      let mut parts = field.splitn(2, '=');
+      let (key, value) = (parts.next().unwrap(), parts.next().unwrap_or("0"));
+      match key {
+        "avg10" => pressure.avg10 = value.parse()?,
+        "avg60" => pressure.avg60 = value.parse()?,
+        "avg300" => pressure.avg300 = value.parse()?,
+        "total" => pressure.total = value.parse()?,
+        _ => {}
+      }
+    }
+    Ok(Some(pressure))
+  }
+}
+
+/// Read a file holding a single number, `None` if it holds `max` or if it
+/// doesn't exist.
+pub fn read_value(path: &Path) -> Result<Option<u64>> {
+  if !path.exists() {
+    return Ok(None);
+  }
+
+  match fs::read_to_string(path)?.trim() {
+    "max" => Ok(None),
+    value => Ok(Some(value.parse()?)),
+  }
+}
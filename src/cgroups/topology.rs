@@ -0,0 +1,83 @@
+use super::CgroupError;
+use crate::Result;
+use std::fs;
+
+const ONLINE_CPUS: &str = "/sys/devices/system/cpu/online";
+const ONLINE_NODES: &str = "/sys/devices/system/node/online";
+/// The kernel supports at most 8192 CPUs, and fewer memory nodes, so larger
+/// indices can't be online.
+const MAX_INDEX: u32 = 8191;
+
+/// Parse a cpu or memory node list like `0-3,6` into the indices it contains.
+pub fn parse_list(list: &str) -> Result<Vec<u32>> {
+  let invalid = || CgroupError::InvalidValue(list.to_string());
+  let mut indices = vec![];
+  for range in list.trim().split(',') {
+    let mut bounds = range.splitn(2, '-');
+    let start: u32 = bounds
+      .next()
+      .unwrap_or_default()
+      .parse()
+      .map_err(|_| invalid())?;
+    let end: u32 = match bounds.next() {
+      Some(end) => end.parse().map_err(|_| invalid())?,
+      None => start,
+    };
+    if end < start || end > MAX_INDEX {
+      return Err(Box::new(invalid()));
+    }
+    indices.extend(start..=end);
+  }
+  Ok(indices)
+}
+
+/// CPUs that are online on the host.
+pub fn online_cpus() -> Result<Vec<u32>> {
+  parse_list(&fs::read_to_string(ONLINE_CPUS)?)
+}
+
+/// Memory nodes that are online on the host. Hosts without NUMA support only
+/// have the node 0.
+pub fn online_nodes() -> Result<Vec<u32>> {
+  match fs::read_to_string(ONLINE_NODES) {
+    Ok(nodes) => parse_list(&nodes),
+    Err(_) => Ok(vec![0]),
+  }
+}
+
+/// Check that every index of the list is available on the host.
+pub fn validate_list(list: &str, online: &[u32], option: &'static str) -> Result<()> {
+  for index in parse_list(list)? {
+    if !online.contains(&index) {
+      return Err(Box::new(CgroupError::InvalidResources(option)));
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_list_ranges() {
+    assert_eq!(parse_list("0").unwrap(), vec![0]);
+    assert_eq!(parse_list("0-3,6\n").unwrap(), vec![0, 1, 2, 3, 6]);
+    assert_eq!(parse_list("2-2").unwrap(), vec![2]);
+  }
+
+  #[test]
+  fn parse_list_invalid() {
+    for list in &["", "3-1", "-1", "1-", "a", "0,,1", "0-1-2"] {
+      assert!(parse_list(list).is_err(), "{}", list);
+    }
+  }
+
+  #[test]
+  fn parse_list_bounds() {
+    assert_eq!(parse_list("0-8191").unwrap().len(), 8192);
+    assert!(parse_list("8192").is_err());
+    assert!(parse_list("0-4294967295").is_err());
+    assert!(parse_list("4294967296").is_err());
+  }
+}
@@ -0,0 +1,371 @@
+use super::stats::read_value;
+use super::{
+  limit_value, read_groups, read_key, read_tasks, wait_for, write, write_memory_limit, Cgroup,
+  CgroupError, CpuStats, IoStats, MemoryStats, OomEvents, PidsStats, Resources, Stats,
+  CGROUP_PARENT, CGROUP_ROOT,
+};
+use crate::devices::DeviceRule;
+use crate::Result;
+use nix::libc;
+use nix::sys::eventfd::{eventfd, EfdFlags};
+use nix::unistd::Pid;
+use std::fs::{self, File};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Controllers we put the container in. Every v1 controller is mounted as a
+/// separate hierarchy under the cgroup root.
+const CONTROLLERS: &[&str] = &[
+  "pids", "memory", "cpu", "cpuacct", "cpuset", "blkio", "freezer", "devices",
+];
+/// v1 reports a memory limit close to the maximum of a 64 bit integer for no
+/// limit. Anything above this is considered unlimited.
+const UNLIMITED_MEMORY: u64 = 1 << 62;
+
+/// Cgroup backend for the legacy v1 hierarchies, where the container gets a
+/// group in every controller hierarchy.
+pub struct CgroupV1 {
+  name: String,
+}
+
+impl CgroupV1 {
+  pub fn new(name: &str) -> Self {
+    CgroupV1 {
+      name: name.to_string(),
+    }
+  }
+
+  /// Path of the container's group inside the given controller hierarchy.
+  fn path(&self, controller: &str) -> PathBuf {
+    let mut path = PathBuf::from(CGROUP_ROOT);
+    path.push(controller);
+    path.push(CGROUP_PARENT);
+    path.push(&self.name);
+    path
+  }
+
+  fn apply_memory(&self, resources: &Resources) -> Result<()> {
+    // The memory files of v1 take -1 instead of "max" for no limit.
+    let memory = self.path("memory");
+    if let Some(reservation) = resources.memory_reservation {
+      write(
+        &memory.join("memory.soft_limit_in_bytes"),
+        &reservation.to_string(),
+      )?;
+    }
+
+    let memsw = memory.join("memory.memsw.limit_in_bytes");
+    let swap = match resources.memory_swap {
+      Some(_) if !memsw.exists() => {
+        warn!("Swap accounting is disabled on the host, ignoring the swap limit");
+        None
+      }
+      swap => swap,
+    };
+    // The memory limit can't be above the memory plus swap limit. Raise the
+    // swap limit first when it grows, and lower it last when it shrinks.
+    let swap_grows = match swap {
+      Some(swap) => swap < 0 || swap as u64 >= fs::read_to_string(&memsw)?.trim().parse()?,
+      None => false,
+    };
+    if let (Some(swap), true) = (swap, swap_grows) {
+      write_memory_limit(&memsw, &swap.to_string())?;
+    }
+    if let Some(limit) = resources.memory {
+      write_memory_limit(&memory.join("memory.limit_in_bytes"), &limit.to_string())?;
+    }
+    if let (Some(swap), false) = (swap, swap_grows) {
+      write_memory_limit(&memsw, &swap.to_string())?;
+    }
+    if resources.oom_kill_disable {
+      write(&memory.join("memory.oom_control"), "1")?;
+    }
+    Ok(())
+  }
+
+  fn apply_cpu(&self, resources: &Resources) -> Result<()> {
+    let cpu = self.path("cpu");
+    if let Some(shares) = resources.cpu_shares {
+      write(&cpu.join("cpu.shares"), &shares.to_string())?;
+    }
+    // The period goes first, the kernel checks the quota against it.
+    if let Some(period) = resources.cpu_period {
+      write(&cpu.join("cpu.cfs_period_us"), &period.to_string())?;
+    }
+    if let Some(quota) = resources.cpu_quota {
+      write(&cpu.join("cpu.cfs_quota_us"), &quota.to_string())?;
+    }
+
+    let cpuset = self.path("cpuset");
+    if let Some(cpus) = &resources.cpuset_cpus {
+      write(&cpuset.join("cpuset.cpus"), cpus)?;
+    }
+    if let Some(mems) = &resources.cpuset_mems {
+      write(&cpuset.join("cpuset.mems"), mems)?;
+    }
+    Ok(())
+  }
+
+  fn apply_blkio(&self, resources: &Resources) -> Result<()> {
+    let blkio = self.path("blkio");
+    if let Some(weight) = resources.blkio_weight {
+      // Kernels using the BFQ scheduler only have the BFQ weight file.
+      let mut file = blkio.join("blkio.weight");
+      if !file.exists() {
+        file = blkio.join("blkio.bfq.weight");
+      }
+      write(&file, &weight.to_string())?;
+    }
+
+    let throttles = [
+      ("blkio.throttle.read_bps_device", &resources.device_read_bps),
+      (
+        "blkio.throttle.write_bps_device",
+        &resources.device_write_bps,
+      ),
+      (
+        "blkio.throttle.read_iops_device",
+        &resources.device_read_iops,
+      ),
+      (
+        "blkio.throttle.write_iops_device",
+        &resources.device_write_iops,
+      ),
+    ];
+    for (file, devices) in throttles.iter() {
+      for device in devices.iter() {
+        write(
+          &blkio.join(file),
+          &format!("{} {}", device.device(), device.rate),
+        )?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Move the freezer into the given state. The kernel might stay in the
+  /// `FREEZING` state if a task couldn't be frozen yet, writing the state
+  /// again retries them.
+  fn set_freezer_state(&self, state: &str) -> Result<()> {
+    let file = self.path("freezer").join("freezer.state");
+    wait_for(|| {
+      write(&file, state)?;
+      Ok(fs::read_to_string(&file)?.trim() == state)
+    })
+  }
+
+  /// New cpuset groups start without any CPUs and memory nodes, and no task
+  /// can join them like that. Inherit them from the parent group for both our
+  /// parent and the container group.
+  fn init_cpuset(&self) -> Result<()> {
+    let path = self.path("cpuset");
+    for group in &[path.parent().unwrap(), path.as_path()] {
+      let parent = group.parent().unwrap();
+      for file in &["cpuset.cpus", "cpuset.mems"] {
+        if fs::read_to_string(group.join(file))?.trim().is_empty() {
+          write(
+            &group.join(file),
+            fs::read_to_string(parent.join(file))?.trim(),
+          )?;
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+impl Cgroup for CgroupV1 {
+  fn create(&self) -> Result<()> {
+    // Some controllers share a hierarchy, like `cpu,cpuacct`.
+    let mut hierarchies = vec![];
+    for controller in CONTROLLERS {
+      let hierarchy = PathBuf::from(CGROUP_ROOT).join(controller);
+      if !hierarchy.exists() {
+        return Err(Box::new(CgroupError::ControllerNotAvailable(
+          controller.to_string(),
+        )));
+      }
+      let hierarchy = hierarchy.canonicalize()?;
+      if hierarchies.contains(&hierarchy) {
+        continue;
+      }
+      hierarchies.push(hierarchy);
+
+      let path = self.path(controller);
+      if path.exists() {
+        // Shouldn't happen
+        warn!("Unexpected existing cgroup {:?}", path);
+      } else {
+        fs::create_dir_all(&path)?;
+      }
+    }
+    self.init_cpuset()
+  }
+
+  fn apply(&self, resources: &Resources) -> Result<()> {
+    if let Some(pids_max) = resources.pids_max {
+      write(&self.path("pids").join("pids.max"), &limit_value(pids_max))?;
+    }
+    self.apply_memory(resources)?;
+    self.apply_cpu(resources)?;
+    self.apply_blkio(resources)
+  }
+
+  fn apply_devices(&self, rules: &[DeviceRule]) -> Result<()> {
+    let devices = self.path("devices");
+    write(&devices.join("devices.deny"), "a")?;
+    for rule in rules {
+      write(&devices.join("devices.allow"), &rule.to_string())?;
+    }
+    Ok(())
+  }
+
+  fn add_task(&self, pid: Pid) -> Result<()> {
+    for controller in CONTROLLERS {
+      write(
+        &self.path(controller).join("cgroup.procs"),
+        &pid.to_string(),
+      )?;
+    }
+    Ok(())
+  }
+
+  fn oom_kills(&self) -> Result<u64> {
+    read_key(&self.path("memory").join("memory.oom_control"), "oom_kill")
+  }
+
+  fn oom_events(&self) -> Result<OomEvents> {
+    // The kernel signals the eventfd when the group runs out of memory, once
+    // it's registered for the memory.oom_control file.
+    let memory = self.path("memory");
+    let control = File::open(memory.join("memory.oom_control"))?;
+    let event = eventfd(0, EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK)?;
+    let events = OomEvents::new(event, memory.join("memory.oom_control"));
+    write(
+      &memory.join("cgroup.event_control"),
+      &format!("{} {}", event, control.as_raw_fd()),
+    )?;
+    Ok(events)
+  }
+
+  fn stats(&self) -> Result<Stats> {
+    // cpuacct.stat is in clock ticks while everything else is in
+    // microseconds.
+    let cpuacct = self.path("cpuacct");
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+    let cpu_stat = self.path("cpu").join("cpu.stat");
+    let cpu = CpuStats {
+      usage_usec: read_value(&cpuacct.join("cpuacct.usage"))?.unwrap_or(0) / 1000,
+      user_usec: read_key(&cpuacct.join("cpuacct.stat"), "user")? * 1_000_000 / ticks,
+      system_usec: read_key(&cpuacct.join("cpuacct.stat"), "system")? * 1_000_000 / ticks,
+      nr_periods: read_key(&cpu_stat, "nr_periods")?,
+      nr_throttled: read_key(&cpu_stat, "nr_throttled")?,
+      throttled_usec: read_key(&cpu_stat, "throttled_time")? / 1000,
+    };
+
+    let memory = self.path("memory");
+    let memory = MemoryStats {
+      usage: read_value(&memory.join("memory.usage_in_bytes"))?.unwrap_or(0),
+      limit: read_value(&memory.join("memory.limit_in_bytes"))?
+        .filter(|limit| *limit < UNLIMITED_MEMORY),
+      cache: read_key(&memory.join("memory.stat"), "cache")?,
+    };
+
+    let pids = self.path("pids");
+    let pids = PidsStats {
+      current: read_value(&pids.join("pids.current"))?.unwrap_or(0),
+      limit: read_value(&pids.join("pids.max"))?,
+    };
+
+    let blkio = self.path("blkio");
+    let (read_bytes, write_bytes) = read_blkio(&blkio.join("blkio.throttle.io_service_bytes"))?;
+    let (read_ops, write_ops) = read_blkio(&blkio.join("blkio.throttle.io_serviced"))?;
+    let io = IoStats {
+      read_bytes,
+      write_bytes,
+      read_ops,
+      write_ops,
+    };
+
+    // Pressure stall information is only exposed by cgroup v2.
+    Ok(Stats {
+      cpu,
+      memory,
+      pids,
+      io,
+      pressure: None,
+    })
+  }
+
+  fn tasks(&self) -> Result<Vec<Pid>> {
+    let mut tasks = vec![];
+    for controller in CONTROLLERS {
+      for pid in read_tasks(&self.path(controller).join("cgroup.procs"))? {
+        if !tasks.contains(&pid) {
+          tasks.push(pid);
+        }
+      }
+    }
+    Ok(tasks)
+  }
+
+  fn freeze(&self) -> Result<()> {
+    self.set_freezer_state("FROZEN")
+  }
+
+  fn thaw(&self) -> Result<()> {
+    self.set_freezer_state("THAWED")
+  }
+
+  fn paths(&self) -> Vec<PathBuf> {
+    CONTROLLERS
+      .iter()
+      .map(|controller| self.path(controller))
+      .collect()
+  }
+
+  fn remove(&self) -> Result<()> {
+    for controller in CONTROLLERS {
+      let path = self.path(controller);
+      if path.exists() {
+        fs::remove_dir(path)?;
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Sum the reads and writes of every device in a blkio file, where every line
+/// looks like `8:0 Read 4096`.
+fn read_blkio(path: &Path) -> Result<(u64, u64)> {
+  let (mut read, mut write) = (0, 0);
+  if !path.exists() {
+    return Ok((read, write));
+  }
+
+  for line in fs::read_to_string(path)?.lines() {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    match fields.as_slice() {
+      [_, "Read", value] => read += value.parse::<u64>()?,
+      [_, "Write", value] => write += value.parse::<u64>()?,
+      _ => {}
+    }
+  }
+  Ok((read, write))
+}
+
+/// Names of our groups in any of the controller hierarchies.
+pub fn list() -> Result<Vec<String>> {
+  let mut groups = vec![];
+  for controller in CONTROLLERS {
+    let mut path = PathBuf::from(CGROUP_ROOT);
+    path.push(controller);
+    path.push(CGROUP_PARENT);
+    for group in read_groups(&path)? {
+      if !groups.contains(&group) {
+        groups.push(group);
+      }
+    }
+  }
+  Ok(groups)
+}
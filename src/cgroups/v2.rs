@@ -0,0 +1,333 @@
+use super::bpf;
+use super::stats::read_value;
+use super::{
+  limit_value, read_groups, read_key, read_tasks, wait_for, write, write_memory_limit, Cgroup,
+  CgroupError, CpuStats, IoStats, MemoryStats, OomEvents, PidsStats, Pressure, PressureStats,
+  Resources, Stats, CGROUP_PARENT, CGROUP_ROOT, DEFAULT_CPU_PERIOD,
+};
+use crate::devices::DeviceRule;
+use crate::Result;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use nix::unistd::Pid;
+use std::fs;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+/// Controllers we enable for every container group when the host has them,
+/// the statistics of the containers come from them. The others are only
+/// enabled for the limits that need them.
+const STATS_CONTROLLERS: &[&str] = &["pids", "memory", "cpu"];
+
+/// Cgroup backend for the v2 unified hierarchy. The container group is a leaf
+/// under the `container-rs/` parent, which delegates the controllers to it.
+pub struct CgroupV2 {
+  name: String,
+}
+
+impl CgroupV2 {
+  pub fn new(name: &str) -> Self {
+    CgroupV2 {
+      name: name.to_string(),
+    }
+  }
+
+  fn parent_path(&self) -> PathBuf {
+    PathBuf::from(CGROUP_ROOT).join(CGROUP_PARENT)
+  }
+
+  fn path(&self) -> PathBuf {
+    self.parent_path().join(&self.name)
+  }
+
+  /// Enable the controllers for the children of the root and of our parent
+  /// group. A controller has to be enabled in every ancestor of a group
+  /// before its files show up there. The limits can't do without their
+  /// controllers, the statistics only miss what a missing one accounts.
+  fn enable_controllers(&self, controllers: &[&str], required: bool) -> Result<()> {
+    let root = PathBuf::from(CGROUP_ROOT);
+    let available = fs::read_to_string(root.join("cgroup.controllers"))?;
+    let available: Vec<&str> = available.split_whitespace().collect();
+
+    for controller in controllers {
+      if !available.contains(controller) {
+        if required {
+          return Err(Box::new(CgroupError::ControllerNotAvailable(
+            controller.to_string(),
+          )));
+        }
+        warn!(
+          "The {} controller is not available on the host, its statistics are left out",
+          controller
+        );
+        continue;
+      }
+
+      for group in &[root.clone(), self.parent_path()] {
+        let file = group.join("cgroup.subtree_control");
+        let enabled = fs::read_to_string(&file)?;
+        if !enabled.split_whitespace().any(|name| name == *controller) {
+          write(&file, &format!("+{}", controller))?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn apply_memory(&self, resources: &Resources) -> Result<()> {
+    let path = self.path();
+    if let Some(reservation) = resources.memory_reservation {
+      write(&path.join("memory.low"), &limit_value(reservation))?;
+    }
+    if let Some(limit) = resources.memory {
+      write_memory_limit(&path.join("memory.max"), &limit_value(limit))?;
+    }
+    if let Some(swap) = resources.memory_swap {
+      // v2 limits the swap alone, while the option limits memory plus swap.
+      let swap_max = path.join("memory.swap.max");
+      let swap = match resources.memory {
+        Some(memory) if swap >= 0 && memory >= 0 => swap - memory,
+        _ => -1,
+      };
+      if swap_max.exists() {
+        write_memory_limit(&swap_max, &limit_value(swap))?;
+      } else {
+        warn!("Swap accounting is disabled on the host, ignoring the swap limit");
+      }
+    }
+    if resources.oom_kill_disable {
+      warn!("The OOM killer can't be disabled with cgroup v2, ignoring it");
+    }
+    Ok(())
+  }
+
+  fn apply_cpu(&self, resources: &Resources) -> Result<()> {
+    let path = self.path();
+    if let Some(weight) = resources.cpu_weight() {
+      write(&path.join("cpu.weight"), &weight.to_string())?;
+    }
+    if resources.cpu_quota.is_some() || resources.cpu_period.is_some() {
+      let quota = limit_value(resources.cpu_quota.unwrap_or(-1));
+      let period = resources.cpu_period.unwrap_or(DEFAULT_CPU_PERIOD);
+      write(&path.join("cpu.max"), &format!("{} {}", quota, period))?;
+    }
+    if let Some(cpus) = &resources.cpuset_cpus {
+      write(&path.join("cpuset.cpus"), cpus)?;
+    }
+    if let Some(mems) = &resources.cpuset_mems {
+      write(&path.join("cpuset.mems"), mems)?;
+    }
+    Ok(())
+  }
+
+  fn apply_io(&self, resources: &Resources) -> Result<()> {
+    let path = self.path();
+    // Kernels using the BFQ scheduler only have the BFQ weight file, which
+    // takes the weight in the v1 range.
+    let file = path.join("io.weight");
+    if let (Some(weight), true) = (resources.io_weight(), file.exists()) {
+      write(&file, &format!("default {}", weight))?;
+    } else if let Some(weight) = resources.blkio_weight {
+      write(&path.join("io.bfq.weight"), &format!("default {}", weight))?;
+    }
+
+    // Every key of io.max is set separately, the keys we don't write keep
+    // their previous value.
+    let throttles = [
+      ("rbps", &resources.device_read_bps),
+      ("wbps", &resources.device_write_bps),
+      ("riops", &resources.device_read_iops),
+      ("wiops", &resources.device_write_iops),
+    ];
+    for (key, devices) in throttles.iter() {
+      for device in devices.iter() {
+        write(
+          &path.join("io.max"),
+          &format!("{} {}={}", device.device(), key, device.rate),
+        )?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Freeze or thaw the group, and wait until `cgroup.events` confirms it.
+  fn set_frozen(&self, frozen: bool) -> Result<()> {
+    let value = if frozen { 1 } else { 0 };
+    write(&self.path().join("cgroup.freeze"), &value.to_string())?;
+    let events = self.path().join("cgroup.events");
+    wait_for(|| Ok(read_key(&events, "frozen")? == value))
+  }
+
+  /// Sum the IO of every device listed in `io.stat`.
+  fn io_stats(&self) -> Result<IoStats> {
+    let mut io = IoStats::default();
+    let path = self.path().join("io.stat");
+    if !path.exists() {
+      return Ok(io);
+    }
+
+    for line in fs::read_to_string(path)?.lines() {
+      // Every line starts with the device, followed by `key=value` pairs.
+      for field in line.split_whitespace().skip(1) {
+        let mut parts = field.splitn(2, '=');
+        let (key, value) = (parts.next().unwrap(), parts.next().unwrap_or("0"));
+        let counter = match key {
+          "rbytes" => &mut io.read_bytes,
+          "wbytes" => &mut io.write_bytes,
+          "rios" => &mut io.read_ops,
+          "wios" => &mut io.write_ops,
+          _ => continue,
+        };
+        *counter += value.parse::<u64>()?;
+      }
+    }
+    Ok(io)
+  }
+}
+
+impl Cgroup for CgroupV2 {
+  fn create(&self) -> Result<()> {
+    let parent = self.parent_path();
+    if !parent.exists() {
+      fs::create_dir(&parent)?;
+    }
+    self.enable_controllers(STATS_CONTROLLERS, false)?;
+
+    let path = self.path();
+    if path.exists() {
+      // Shouldn't happen
+      warn!("Unexpected existing cgroup {:?}", path);
+    } else {
+      fs::create_dir(&path)?;
+    }
+    Ok(())
+  }
+
+  fn apply(&self, resources: &Resources) -> Result<()> {
+    self.enable_controllers(&required_controllers(resources), true)?;
+    if let Some(pids_max) = resources.pids_max {
+      write(&self.path().join("pids.max"), &limit_value(pids_max))?;
+    }
+    self.apply_memory(resources)?;
+    self.apply_cpu(resources)?;
+    self.apply_io(resources)
+  }
+
+  fn apply_devices(&self, rules: &[DeviceRule]) -> Result<()> {
+    bpf::attach_device_filter(&self.path(), rules)
+  }
+
+  fn add_task(&self, pid: Pid) -> Result<()> {
+    write(&self.path().join("cgroup.procs"), &pid.to_string())
+  }
+
+  fn oom_kills(&self) -> Result<u64> {
+    read_key(&self.path().join("memory.events"), "oom_kill")
+  }
+
+  fn oom_events(&self) -> Result<OomEvents> {
+    // The kernel notifies the watchers of memory.events whenever one of its
+    // counters changes.
+    let file = self.path().join("memory.events");
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC | InitFlags::IN_NONBLOCK)?;
+    let events = OomEvents::new(inotify.as_raw_fd(), file.clone());
+    inotify.add_watch(&file, AddWatchFlags::IN_MODIFY)?;
+    Ok(events)
+  }
+
+  fn stats(&self) -> Result<Stats> {
+    let path = self.path();
+    let cpu_stat = path.join("cpu.stat");
+    let cpu = CpuStats {
+      usage_usec: read_key(&cpu_stat, "usage_usec")?,
+      user_usec: read_key(&cpu_stat, "user_usec")?,
+      system_usec: read_key(&cpu_stat, "system_usec")?,
+      nr_periods: read_key(&cpu_stat, "nr_periods")?,
+      nr_throttled: read_key(&cpu_stat, "nr_throttled")?,
+      throttled_usec: read_key(&cpu_stat, "throttled_usec")?,
+    };
+    let memory = MemoryStats {
+      usage: read_value(&path.join("memory.current"))?.unwrap_or(0),
+      limit: read_value(&path.join("memory.max"))?,
+      cache: read_key(&path.join("memory.stat"), "file")?,
+    };
+    let pids = PidsStats {
+      current: read_value(&path.join("pids.current"))?.unwrap_or(0),
+      limit: read_value(&path.join("pids.max"))?,
+    };
+    let pressure = PressureStats {
+      cpu: Pressure::read(&path.join("cpu.pressure"))?,
+      memory: Pressure::read(&path.join("memory.pressure"))?,
+      io: Pressure::read(&path.join("io.pressure"))?,
+    };
+    let has_pressure = pressure.cpu.is_some() || pressure.memory.is_some() || pressure.io.is_some();
+
+    Ok(Stats {
+      cpu,
+      memory,
+      pids,
+      io: self.io_stats()?,
+      pressure: if has_pressure { Some(pressure) } else { None },
+    })
+  }
+
+  fn tasks(&self) -> Result<Vec<Pid>> {
+    read_tasks(&self.path().join("cgroup.procs"))
+  }
+
+  fn freeze(&self) -> Result<()> {
+    self.set_frozen(true)
+  }
+
+  fn thaw(&self) -> Result<()> {
+    self.set_frozen(false)
+  }
+
+  fn paths(&self) -> Vec<PathBuf> {
+    vec![self.path()]
+  }
+
+  fn remove(&self) -> Result<()> {
+    let path = self.path();
+    if path.exists() {
+      fs::remove_dir(path)?;
+    }
+    Ok(())
+  }
+}
+
+/// Names of the groups under our parent group.
+pub fn list() -> Result<Vec<String>> {
+  read_groups(&PathBuf::from(CGROUP_ROOT).join(CGROUP_PARENT))
+}
+
+/// The controllers the limits need.
+fn required_controllers(resources: &Resources) -> Vec<&'static str> {
+  let mut controllers = vec![];
+  if resources.pids_max.is_some() {
+    controllers.push("pids");
+  }
+  if resources.memory.is_some()
+    || resources.memory_swap.is_some()
+    || resources.memory_reservation.is_some()
+  {
+    controllers.push("memory");
+  }
+  if resources.cpu_shares.is_some()
+    || resources.cpu_quota.is_some()
+    || resources.cpu_period.is_some()
+  {
+    controllers.push("cpu");
+  }
+  if resources.cpuset_cpus.is_some() || resources.cpuset_mems.is_some() {
+    controllers.push("cpuset");
+  }
+  if resources.blkio_weight.is_some()
+    || !resources.device_read_bps.is_empty()
+    || !resources.device_write_bps.is_empty()
+    || !resources.device_read_iops.is_empty()
+    || !resources.device_write_iops.is_empty()
+  {
+    controllers.push("io");
+  }
+  controllers
+}
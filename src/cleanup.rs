@@ -0,0 +1,26 @@
+use crate::cgroups;
+use crate::state::ContainerState;
+use crate::Result;
+
+/// Main entry for the cleanup subcommand. Removes the cgroups that are left
+/// behind by containers which are not running anymore, e.g. after a crash.
+pub fn cleanup(_args: &clap::ArgMatches<'static>) -> Result<()> {
+  for name in cgroups::list()? {
+    // The state is saved once the cgroup is set up, so a cgroup without one
+    // may belong to a container that is starting.
+    let state = match ContainerState::load(&name) {
+      Ok(state) => state,
+      Err(err) => {
+        debug!("Skipping the cgroup {} without a state: {}", name, err);
+        continue;
+      }
+    };
+    if state.is_running() || state.is_restarting() {
+      continue;
+    }
+
+    info!("Removing the orphaned cgroup {}", name);
+    cgroups::destroy(&*cgroups::new(&name)?)?;
+  }
+  Ok(())
+}
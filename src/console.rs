@@ -0,0 +1,344 @@
+use crate::fs::get_container_path;
+use crate::logs::{Logger, Stream};
+use crate::tty;
+use nix::libc;
+use nix::pty::Winsize;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::{close, Pid};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread;
+
+const BUFFER_SIZE: usize = 4096;
+/// The largest payload a frame can have. Ours are at most a buffer of output
+/// or input.
+const MAX_PAYLOAD: usize = 64 * 1024;
+/// How many frames of output a client can fall behind before it's dropped,
+/// so that a client that stops reading doesn't stall the container.
+const CLIENT_BACKLOG: usize = 1024;
+
+const STDIN: u8 = 0;
+const STDOUT: u8 = 1;
+const STDERR: u8 = 2;
+const CLOSE_STDIN: u8 = 3;
+const RESIZE: u8 = 4;
+const READY: u8 = 5;
+const FAILED: u8 = 6;
+const EXIT: u8 = 7;
+const SIGNAL: u8 = 8;
+const STOP: u8 = 9;
+
+/// The socket the supervisor of the container listens on for clients.
+pub fn console_path(id: &str) -> PathBuf {
+  get_container_path(id).join("console.sock")
+}
+
+/// A message between the supervisor of a container and a client, written
+/// as its kind, the length of its payload and the payload.
+#[derive(Debug, Clone)]
+pub enum Frame {
+  /// Input of the user for the container.
+  Stdin(Vec<u8>),
+  /// Output of the container.
+  Stdout(Vec<u8>),
+  Stderr(Vec<u8>),
+  /// The user has no more input.
+  CloseStdin,
+  /// The terminal of the user got resized, to its rows and columns.
+  Resize(u16, u16),
+  /// The container is created, with its ID.
+  Ready(String),
+  /// The container couldn't be created.
+  Failed(String),
+  /// The container exited, with its exit code.
+  Exit(i32),
+  /// A signal for the init process of the container, by its number.
+  Signal(i32),
+  /// Stop the container with the signal, and don't restart it.
+  Stop(i32),
+}
+
+impl Frame {
+  pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+    let (kind, payload) = match self {
+      Frame::Stdin(data) => (STDIN, data.clone()),
+      Frame::Stdout(data) => (STDOUT, data.clone()),
+      Frame::Stderr(data) => (STDERR, data.clone()),
+      Frame::CloseStdin => (CLOSE_STDIN, vec![]),
+      Frame::Resize(rows, columns) => {
+        let mut payload = rows.to_be_bytes().to_vec();
+        payload.extend_from_slice(&columns.to_be_bytes());
+        (RESIZE, payload)
+      }
+      Frame::Ready(id) => (READY, id.as_bytes().to_vec()),
+      Frame::Failed(message) => (FAILED, message.as_bytes().to_vec()),
+      Frame::Exit(code) => (EXIT, code.to_be_bytes().to_vec()),
+      Frame::Signal(signal) => (SIGNAL, signal.to_be_bytes().to_vec()),
+      Frame::Stop(signal) => (STOP, signal.to_be_bytes().to_vec()),
+    };
+    // A single write, so that frames written from several threads don't get
+    // mixed.
+    let mut frame = vec![kind];
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    writer.write_all(&frame)
+  }
+
+  /// Read the next frame, `None` once the other side closed the socket.
+  pub fn read(reader: &mut impl Read) -> io::Result<Option<Self>> {
+    let mut header = [0; 5];
+    match reader.read_exact(&mut header) {
+      Ok(()) => {}
+      Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+      Err(err) => return Err(err),
+    }
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid console frame");
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if len > MAX_PAYLOAD {
+      return Err(invalid());
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+
+    let text = |payload: Vec<u8>| String::from_utf8(payload).map_err(|_| invalid());
+    let number =
+      |payload: &[u8]| i32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+    Ok(Some(match header[0] {
+      STDIN => Frame::Stdin(payload),
+      STDOUT => Frame::Stdout(payload),
+      STDERR => Frame::Stderr(payload),
+      CLOSE_STDIN => Frame::CloseStdin,
+      RESIZE if payload.len() == 4 => Frame::Resize(
+        u16::from_be_bytes([payload[0], payload[1]]),
+        u16::from_be_bytes([payload[2], payload[3]]),
+      ),
+      READY => Frame::Ready(text(payload)?),
+      FAILED => Frame::Failed(text(payload)?),
+      EXIT if payload.len() == 4 => Frame::Exit(number(&payload)),
+      SIGNAL if payload.len() == 4 => Frame::Signal(number(&payload)),
+      STOP if payload.len() == 4 => Frame::Stop(number(&payload)),
+      _ => return Err(invalid()),
+    }))
+  }
+}
+
+/// The standard streams of a container, held by its supervisor. The output
+/// goes to the log and to every attached client, the input of the clients
+/// goes to the container.
+pub struct Console {
+  logger: Mutex<Logger>,
+  clients: Mutex<Vec<Client>>,
+  process: Mutex<Process>,
+}
+
+/// An attached client. The output is written to it from a thread of its own,
+/// the container never waits for a client.
+struct Client {
+  frames: SyncSender<Frame>,
+  stream: UnixStream,
+}
+
+impl Client {
+  fn new(stream: &UnixStream) -> io::Result<Self> {
+    let (frames, backlog) = mpsc::sync_channel::<Frame>(CLIENT_BACKLOG);
+    let mut writer = stream.try_clone()?;
+    thread::spawn(move || {
+      for frame in backlog {
+        if frame.write(&mut writer).is_err() {
+          break;
+        }
+      }
+    });
+    Ok(Client {
+      frames,
+      stream: stream.try_clone()?,
+    })
+  }
+
+  /// Queue the frame for the client. Returns false once the client is gone,
+  /// or got disconnected for falling too far behind.
+  fn send(&self, frame: Frame) -> bool {
+    match self.frames.try_send(frame) {
+      Ok(()) => true,
+      Err(TrySendError::Full(_)) => {
+        warn!("Dropping a console client that doesn't keep up with the output");
+        let _ = self.stream.shutdown(Shutdown::Both);
+        false
+      }
+      Err(TrySendError::Disconnected(_)) => false,
+    }
+  }
+}
+
+/// The current run of the container.
+struct Process {
+  /// The init process of the container, until it exits.
+  pid: Option<Pid>,
+  /// The pseudo-terminal or the stdin pipe of the container, if it reads
+  /// input.
+  input: Option<RawFd>,
+  /// The master end of the pseudo-terminal of the container, if it has one.
+  terminal: Option<RawFd>,
+  /// The signal a client stopped the container with.
+  stopped: Option<Signal>,
+}
+
+impl Console {
+  pub fn new(pid: Pid, logger: Logger, input: Option<RawFd>, terminal: Option<RawFd>) -> Self {
+    Console {
+      logger: Mutex::new(logger),
+      clients: Mutex::new(vec![]),
+      process: Mutex::new(Process {
+        pid: Some(pid),
+        input,
+        terminal,
+        stopped: None,
+      }),
+    }
+  }
+
+  /// Switch to the streams of the restarted container. It gets stopped right
+  /// away if a client stopped it meanwhile.
+  pub fn restart(&self, pid: Pid, input: Option<RawFd>, terminal: Option<RawFd>) {
+    let mut process = self.process.lock().unwrap();
+    if let Some(terminal) = process.terminal {
+      let _ = close(terminal);
+    }
+    if let Some(input) = process
+      .input
+      .filter(|&input| Some(input) != process.terminal)
+    {
+      let _ = close(input);
+    }
+    process.pid = Some(pid);
+    process.input = input;
+    process.terminal = terminal;
+    if let Some(signal) = process.stopped {
+      send_signal(process.pid, signal);
+    }
+  }
+
+  /// Whether a client stopped the container, so it must not be restarted.
+  pub fn is_stopped(&self) -> bool {
+    self.process.lock().unwrap().stopped.is_some()
+  }
+
+  /// Send the output to the clients, forgetting the ones that are gone, and
+  /// log it.
+  fn output(&self, stream: Stream, data: &[u8]) {
+    let frame = match stream {
+      Stream::Stdout => Frame::Stdout(data.to_vec()),
+      Stream::Stderr => Frame::Stderr(data.to_vec()),
+    };
+    self
+      .clients
+      .lock()
+      .unwrap()
+      .retain(|client| client.send(frame.clone()));
+    self.logger.lock().unwrap().write(stream, data);
+  }
+
+  /// Relay a stream of the container until every process of the container
+  /// closes it.
+  pub fn capture(&self, source: RawFd, stream: Stream) {
+    let mut source = unsafe { File::from_raw_fd(source) };
+    let mut buf = [0; BUFFER_SIZE];
+    loop {
+      match source.read(&mut buf) {
+        Ok(0) => break,
+        Ok(len) => self.output(stream, &buf[..len]),
+        Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+        // Reading a pseudo-terminal fails with EIO once the slave end is
+        // closed everywhere.
+        Err(err) if err.raw_os_error() == Some(libc::EIO) => break,
+        Err(err) => {
+          warn!("Failed to read the {:?} of the container: {}", stream, err);
+          break;
+        }
+      }
+    }
+    self.logger.lock().unwrap().flush(stream);
+  }
+
+  /// Tell the clients the exit code of the container. They are done with
+  /// it, even if it gets restarted.
+  pub fn exit(&self, code: i32) {
+    self.process.lock().unwrap().pid = None;
+    for client in self.clients.lock().unwrap().drain(..) {
+      client.send(Frame::Exit(code));
+    }
+  }
+
+  /// Send the output that follows to the client.
+  pub fn add_client(&self, client: &UnixStream) -> io::Result<()> {
+    self.clients.lock().unwrap().push(Client::new(client)?);
+    Ok(())
+  }
+
+  /// Handle the input of a client until it disconnects.
+  pub fn serve(&self, mut client: UnixStream) {
+    loop {
+      let frame = match Frame::read(&mut client) {
+        Ok(Some(frame)) => frame,
+        Ok(None) => break,
+        Err(err) => {
+          debug!("Dropping a console client: {}", err);
+          break;
+        }
+      };
+      let mut process = self.process.lock().unwrap();
+      match (frame, process.input, process.terminal) {
+        (Frame::Stdin(data), Some(fd), _) => {
+          if let Err(err) = tty::write_all(fd, &data) {
+            debug!("Failed to write the input of the container: {}", err);
+          }
+        }
+        // The end of the input is a key press on a terminal, only the stdin
+        // pipe gets closed.
+        (Frame::CloseStdin, Some(fd), None) => {
+          let _ = close(fd);
+          process.input = None;
+        }
+        (Frame::Resize(rows, columns), _, Some(terminal)) => {
+          let size = Winsize {
+            ws_row: rows,
+            ws_col: columns,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+          };
+          tty::set_window_size(terminal, &size);
+        }
+        (Frame::Signal(number), _, _) => match Signal::try_from(number) {
+          Ok(signal) => send_signal(process.pid, signal),
+          Err(_) => debug!("Ignoring the unknown signal {}", number),
+        },
+        (Frame::Stop(number), _, _) => match Signal::try_from(number) {
+          Ok(signal) => {
+            process.stopped = Some(signal);
+            send_signal(process.pid, signal);
+          }
+          Err(_) => debug!("Ignoring the unknown signal {}", number),
+        },
+        _ => {}
+      }
+    }
+  }
+}
+
+/// Send the signal to the init process of the container, if it still runs.
+fn send_signal(pid: Option<Pid>, signal: Signal) {
+  if let Some(Err(err)) = pid.map(|pid| kill(pid, signal)) {
+    debug!(
+      "Failed to send {} to the container: {}",
+      signal.as_str(),
+      err
+    );
+  }
+}
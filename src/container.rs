@@ -1,117 +1,428 @@
-use crate::cgroups;
+use crate::cgroups::{self, Cgroup};
+use crate::devices;
+use crate::etc;
 use crate::fs::FileSystem;
-use nix::mount::{mount, umount, MsFlags};
-use nix::sched::{clone, unshare, CloneFlags};
-use nix::sys::signal::Signal;
-use nix::sys::wait::{waitpid, WaitStatus};
-use nix::unistd::{chroot, sethostname, Pid};
+use crate::health;
+use crate::init;
+use crate::logs;
+use crate::network;
+use crate::restart;
+use crate::rlimits;
+use crate::state::{ContainerState, ExitStatus, Status};
+use crate::stop;
+use crate::tty;
+use crate::Result;
+use nix::fcntl::OFlag;
+use nix::mount::{mount, MsFlags};
+use nix::sched::{clone, setns, unshare, CloneFlags};
+use nix::sys::signal::{kill, signal, SigHandler, Signal};
+use nix::sys::wait::waitpid;
+use nix::unistd::{chroot, close, dup2, execvp, pipe, pipe2, read, sethostname, write, Pid};
 use sha2::digest::Digest;
 use sha2::Sha256;
-use std::env::{current_dir, set_current_dir};
-use std::process::{self, Command};
+use std::env::set_current_dir;
+use std::ffi::{CStr, CString};
+use std::os::unix::io::RawFd;
+use std::process;
 use std::time::SystemTime;
 
+/// Ends of the pipes of the standard streams, for containers without a
+/// terminal. The input pipe is only there for interactive containers.
+#[derive(Debug, Clone, Copy)]
+pub struct Pipes {
+  pub stdin: Option<RawFd>,
+  pub stdout: RawFd,
+  pub stderr: RawFd,
+}
+
+impl Pipes {
+  /// Create the pipes, and get our ends and the ends of the container.
+  fn new(interactive: bool) -> Result<(Self, Self)> {
+    let stdin = if interactive {
+      Some(pipe2(OFlag::O_CLOEXEC)?)
+    } else {
+      None
+    };
+    let (stdout, stderr) = (pipe2(OFlag::O_CLOEXEC)?, pipe2(OFlag::O_CLOEXEC)?);
+    Ok((
+      Pipes {
+        stdin: stdin.map(|(_, write)| write),
+        stdout: stdout.0,
+        stderr: stderr.0,
+      },
+      Pipes {
+        stdin: stdin.map(|(read, _)| read),
+        stdout: stdout.1,
+        stderr: stderr.1,
+      },
+    ))
+  }
+
+  fn close(&self) -> Result<()> {
+    if let Some(stdin) = self.stdin {
+      close(stdin)?;
+    }
+    close(self.stdout)?;
+    close(self.stderr)?;
+    Ok(())
+  }
+}
+
 pub struct Container {
   pub id: String,
   pub pid: Pid,
   pub file_system: FileSystem,
+  pub cgroup: Box<dyn Cgroup>,
+  pub state: ContainerState,
+  /// The master end of the pseudo-terminal of the container, if it has one.
+  pub console: Option<RawFd>,
+  /// Our ends of the standard streams, if it has no terminal.
+  pub pipes: Option<Pipes>,
 }
 
 impl Container {
   /// Initialize a new container process and return it.
-  pub fn new(args: &clap::ArgMatches<'static>) -> Self {
+  pub fn new(args: &clap::ArgMatches<'static>) -> Result<Self> {
+    // Parse the resource limits before doing anything, so that a typo doesn't
+    // leave a half created container behind.
+    let rlimits = rlimits::from_args(args)?;
+    let resources = cgroups::Resources::from_args(args)?;
+    let (devices, device_rules) = devices::from_args(args)?;
+    let (network, ip, ports) = network::from_args(args)?;
+    let log = logs::from_args(args)?;
+    let stop_signal = stop::from_args(args)?;
+    let restart_policy = restart::from_args(args)?;
+    let healthcheck = health::from_args(args)?;
+
     // Get the container ID as sha256 from the current timestamp.
     let mut hasher = Sha256::new();
     let unix_timestamp = SystemTime::now()
       .duration_since(SystemTime::UNIX_EPOCH)
       .unwrap()
-      .as_nanos()
-      .to_be_bytes();
+      .as_nanos();
 
-    hasher.input(unix_timestamp);
+    hasher.input(unix_timestamp.to_be_bytes());
     let id = hex::encode(hasher.result());
     info!("Container id: {}", id);
+    let etc = etc::from_args(args, &id)?;
 
     // Create a new filesystem and pass this into the container.
     // TODO: Remove the String clone by sending a reference.
     let file_system = FileSystem::new(args, id.clone());
 
+    // Record the configuration of the container. The child process sets
+    // itself up from it, and other subcommands look the container up later.
+    let mut command = vec![args.value_of("command").unwrap().to_string()];
+    if let Some(command_args) = args.values_of("command_args") {
+      command.extend(command_args.map(String::from));
+    }
+    let state = ContainerState {
+      id: id.clone(),
+      pid: 0,
+      shim_pid: process::id() as i32,
+      image: args.value_of("image").unwrap().to_string(),
+      command,
+      tty: args.is_present("tty"),
+      interactive: args.is_present("interactive"),
+      init: args.is_present("init"),
+      log,
+      created: (unix_timestamp / 1_000_000_000) as u64,
+      started: None,
+      finished: None,
+      status: Status::Running,
+      exit_status: None,
+      restart_policy,
+      restart_count: 0,
+      manually_stopped: false,
+      auto_remove: args.is_present("rm"),
+      stop_signal: stop_signal.as_str().to_string(),
+      healthcheck,
+      rootfs: file_system.path.clone(),
+      rlimits,
+      resources,
+      devices,
+      device_rules,
+      network,
+      ip,
+      ports,
+      etc,
+      endpoint: None,
+    };
+    Self::start(state, file_system)
+  }
+
+  /// Start the container again from its state after it exited, on the same
+  /// root filesystem and with the same address.
+  pub fn restart(self) -> Result<Self> {
+    let mut state = ContainerState::load(&self.id).unwrap_or(self.state);
+    state.status = Status::Running;
+    state.restart_count += 1;
+    if let Some(endpoint) = state.endpoint.take() {
+      state.ip = Some(endpoint.address);
+    }
+    Self::start(state, self.file_system)
+  }
+
+  /// Create the process of the container from its state.
+  fn start(mut state: ContainerState, file_system: FileSystem) -> Result<Self> {
     // Stack creation
     const STACK_SIZE: usize = 1024 * 1024;
     let stack: &mut [u8; STACK_SIZE] = &mut [0; STACK_SIZE];
+    // The cgroup is only created on the host once the container exists.
+    let cgroup = cgroups::new(&state.id)?;
+    // The container waits on this pipe until we are done setting it up from
+    // the outside.
+    let (sync_read, sync_write) = pipe()?;
+    // The network namespace of another container, if the container joins it.
+    let netns = state.network.open_namespace()?;
+    // The socket the container sends its terminal over.
+    let console_socket = if state.tty {
+      Some(tty::console_socket()?)
+    } else {
+      None
+    };
+    let child_console = console_socket.map(|(_, child)| child);
+    // The standard streams go through pipes without a terminal.
+    let pipes = if state.tty {
+      None
+    } else {
+      Some(Pipes::new(state.interactive)?)
+    };
+    let child_pipes = pipes.map(|(_, child)| child);
     // Callback for child process
-    let callback = Box::new(|| child(args));
+    let callback = Box::new(|| {
+      child(
+        &state,
+        netns,
+        child_console,
+        child_pipes,
+        sync_read,
+        sync_write,
+      )
+    });
 
     // Create the flags for the new container process. These flags
     // creates new namespaces and assigns them to the child process.
+    // The cgroup namespace is unshared by the child once it's inside its own
+    // cgroup, so that this cgroup becomes the root of the namespace.
     let flags = CloneFlags::CLONE_NEWNS
       | CloneFlags::CLONE_NEWPID
-      | CloneFlags::CLONE_NEWCGROUP
       | CloneFlags::CLONE_NEWUTS
       | CloneFlags::CLONE_NEWIPC
-      | CloneFlags::CLONE_NEWNET;
+      | state.network.clone_flags();
     // Create the process with the clone syscall. Rust's Command struct
     // is not enough to create a container process because there is no
     // way to pass a clone flag.
     let pid = clone(callback, stack, flags, Some(Signal::SIGCHLD as i32))
       .expect("Container process creation failed!");
+    // Our copies of the ends of the container are of no use to us.
+    let _ = close(sync_read);
+    if let Some(netns) = netns {
+      let _ = close(netns);
+    }
+    if let Some(child_console) = child_console {
+      let _ = close(child_console);
+    }
+    if let Some(child_pipes) = child_pipes {
+      let _ = child_pipes.close();
+    }
+
+    // The container gives up once the sync pipe is closed without a write,
+    // and is killed if anything fails after it continued.
+    let console = Self::set_up(
+      &mut state,
+      pid,
+      &*cgroup,
+      sync_write,
+      console_socket.map(|(socket, _)| socket),
+    );
+    let _ = close(sync_write);
+    if let Some((socket, _)) = console_socket {
+      let _ = close(socket);
+    }
+    let console = match console {
+      Ok(console) => console,
+      Err(err) => {
+        error!("Failed to set up the container: {}", err);
+        if let Some((pipes, _)) = pipes {
+          let _ = pipes.close();
+        }
+        abort(&state, pid, &*cgroup)?;
+        return Err(err);
+      }
+    };
 
     // Return the container struct.
-    Container {
-      id,
+    Ok(Container {
+      id: state.id.clone(),
       pid,
       file_system,
+      cgroup,
+      state,
+      console,
+      pipes: pipes.map(|(pipes, _)| pipes),
+    })
+  }
+
+  /// Move the container into its own cgroup and configure its network before
+  /// it executes anything, then let it continue and record its state.
+  /// Returns the master end of its terminal, if it has one.
+  fn set_up(
+    state: &mut ContainerState,
+    pid: Pid,
+    cgroup: &dyn Cgroup,
+    sync_write: RawFd,
+    console_socket: Option<RawFd>,
+  ) -> Result<Option<RawFd>> {
+    cgroups::setup(cgroup, &state.resources, &state.device_rules, pid)?;
+    network::setup(state, pid)?;
+    etc::write_files(state)?;
+
+    // Let the container continue.
+    write(sync_write, &[0])?;
+    let console = match console_socket {
+      Some(socket) => Some(tty::receive_console(socket)?),
+      None => None,
+    };
+
+    state.pid = i32::from(pid);
+    state.started = Some(
+      SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs(),
+    );
+    if let Err(err) = state.save() {
+      if let Some(console) = console {
+        let _ = close(console);
+      }
+      return Err(err);
     }
+    Ok(console)
   }
 
   /// Wait for the container process until it's done.
-  pub fn wait(&self) -> WaitStatus {
-    waitpid(self.pid, None).expect("Failed to wait the container process")
+  pub fn wait(&self) -> ExitStatus {
+    let status = waitpid(self.pid, None).expect("Failed to wait the container process");
+    // The kernel counts the OOM kills of the cgroup, check it before the
+    // cgroup is gone.
+    let oom_killed = match self.cgroup.oom_kills() {
+      Ok(oom_kills) => oom_kills > 0,
+      Err(err) => {
+        warn!("Failed to read the OOM kills of the container: {}", err);
+        false
+      }
+    };
+    ExitStatus::new(status, oom_killed)
   }
 }
 
-fn child(args: &clap::ArgMatches<'static>) -> isize {
+/// Kill a container that failed to start, and release what was set up for
+/// it. A restarted container keeps its address until it's removed.
+fn abort(state: &ContainerState, pid: Pid, cgroup: &dyn Cgroup) -> Result<()> {
+  let _ = network::teardown(state);
+  if state.restart_count == 0 {
+    let _ = network::release(state);
+  }
+  let _ = kill(pid, Signal::SIGKILL);
+  let _ = waitpid(pid, None);
+  cgroups::destroy(cgroup)
+}
+
+fn child(
+  state: &ContainerState,
+  netns: Option<RawFd>,
+  console: Option<RawFd>,
+  pipes: Option<Pipes>,
+  sync_read: RawFd,
+  sync_write: RawFd,
+) -> isize {
   info!("Child process pid: {}", process::id());
+  close(sync_write).expect("Failed to close the sync pipe");
+
+  // Wait until the parent moves us into the cgroup. The pipe is closed without
+  // a write if the parent fails to set up the container.
+  let mut buf = [0; 1];
+  if read(sync_read, &mut buf) != Ok(1) {
+    error!("The container setup failed, exiting");
+    return 1;
+  }
+  close(sync_read).expect("Failed to close the sync pipe");
+
+  // Join the network namespace of another container.
+  if let Some(netns) = netns {
+    setns(netns, CloneFlags::CLONE_NEWNET).expect("Failed to join the network namespace");
+    close(netns).expect("Failed to close the network namespace");
+  }
+
   // Unshare the namespace
-  unshare(CloneFlags::CLONE_NEWNS).expect("Failed to unshare");
+  unshare(CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWCGROUP).expect("Failed to unshare");
 
-  // Initialize the cgroups
-  cgroups::init(args);
+  // Don't let the mounts below propagate back to the host.
+  const NONE: Option<&'static [u8]> = None;
+  mount(NONE, "/", NONE, MsFlags::MS_REC | MsFlags::MS_PRIVATE, NONE)
+    .expect("Failed to make the mounts private");
 
   // Set the hostname
-  sethostname("container").expect("Failed to set the hostname");
+  sethostname(&state.etc.hostname).expect("Failed to set the hostname");
+
+  // Put the generated /etc files in place while the state directory is still
+  // reachable.
+  etc::mount_files(state).expect("Failed to mount the /etc files");
 
   // Change the root and set the working directory to it.
-  let mut dir = current_dir().expect("Failed to get the current dir");
-  dir.push("new_ubuntu");
-  chroot(&dir).expect("Failed to set root directory");
+  chroot(&state.rootfs).expect("Failed to set root directory");
   set_current_dir("/").expect("Failed to set the current dir");
 
   // Mount the /proc
-  const NONE: Option<&'static [u8]> = None;
   mount(Some("proc"), "proc", Some("proc"), MsFlags::empty(), NONE)
     .expect("Failed to mount the /proc");
 
-  // Create a new process with the given command from the arguments and wait
-  // for it until it's done. Returns the status code of the process.
-  let mut command = Command::new(
-    &args
-      .value_of("command")
-      .expect("Failed to get the command argument"),
-  );
-
-  if let Some(args) = args.values_of("command_args") {
-    info!("subcommand arguments: {:?}", args);
-    command.args(args);
+  // Populate the /dev
+  devices::setup_dev(&state.devices).expect("Failed to set up the /dev");
+
+  // Attach the standard streams, the terminal needs the /dev/pts mounted.
+  if let Some(pipes) = pipes {
+    if let Some(stdin) = pipes.stdin {
+      dup2(stdin, 0).expect("Failed to redirect the stdin");
+    }
+    dup2(pipes.stdout, 1).expect("Failed to redirect the stdout");
+    dup2(pipes.stderr, 2).expect("Failed to redirect the stderr");
+  }
+  tty::setup_child(console, state.interactive).expect("Failed to set up the terminal");
+
+  // Apply the resource limits as the last step, they are inherited by the
+  // command and everything it spawns.
+  for rlimit in &state.rlimits {
+    rlimit.apply().expect("Failed to set the rlimit");
   }
 
-  let status = command
-    .status()
-    .expect("Failed to create child process inside the container");
+  // The command gets the default handling of the signals, Rust ignores
+  // SIGPIPE.
+  unsafe { signal(Signal::SIGPIPE, SigHandler::SigDfl) }.expect("Failed to reset SIGPIPE");
 
-  // Unount the /proc
-  umount("proc").expect("Failed to unmount the /proc");
+  info!("command: {:?}", state.command);
+  let argv: Vec<CString> = state
+    .command
+    .iter()
+    .map(|arg| CString::new(arg.as_str()).expect("Failed to convert the argument"))
+    .collect();
+  let argv: Vec<&CStr> = argv.iter().map(CString::as_c_str).collect();
+
+  // Run the command under the built-in init, which stays the PID 1.
+  if state.init {
+    return match init::run(&argv, state.tty) {
+      Ok(code) => code as isize,
+      Err(err) => {
+        error!("The init of the container failed: {}", err);
+        1
+      }
+    };
+  }
 
-  info!("Child process status inside the container: {}", status);
-  0
+  // Replace the container init with the given command from the arguments.
+  // The mounts above go away together with the mount namespace once it exits.
+  let err = execvp(argv[0], &argv).unwrap_err();
+  error!("Failed to execute the command inside the container: {}", err);
+  127
 }
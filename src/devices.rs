@@ -0,0 +1,338 @@
+use crate::Result;
+use nix::mount::{mount, MsFlags};
+use nix::sys::stat::{major, makedev, minor, mknod, stat, umask, Mode, SFlag};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::Path;
+
+/// Device nodes every container gets in its `/dev`.
+const DEFAULT_DEVICES: &[(&str, u64, u64)] = &[
+  ("/dev/null", 1, 3),
+  ("/dev/zero", 1, 5),
+  ("/dev/full", 1, 7),
+  ("/dev/random", 1, 8),
+  ("/dev/urandom", 1, 9),
+  ("/dev/tty", 5, 0),
+];
+
+/// Links every container gets in its `/dev`.
+const DEFAULT_LINKS: &[(&str, &str)] = &[
+  ("/proc/self/fd", "/dev/fd"),
+  ("/proc/self/fd/0", "/dev/stdin"),
+  ("/proc/self/fd/1", "/dev/stdout"),
+  ("/proc/self/fd/2", "/dev/stderr"),
+  ("pts/ptmx", "/dev/ptmx"),
+];
+
+#[derive(Debug)]
+pub enum DeviceError {
+  InvalidDevice(String),
+  InvalidRule(String),
+  NotADevice(String),
+}
+
+impl fmt::Display for DeviceError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DeviceError::InvalidDevice(device) => write!(f, "Invalid device: {}", device),
+      DeviceError::InvalidRule(rule) => write!(f, "Invalid device cgroup rule: {}", rule),
+      DeviceError::NotADevice(path) => write!(f, "{} is not a device", path),
+    }
+  }
+}
+
+impl Error for DeviceError {}
+
+/// Type of the devices a rule matches.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceType {
+  All,
+  Char,
+  Block,
+}
+
+impl DeviceType {
+  fn as_char(self) -> char {
+    match self {
+      DeviceType::All => 'a',
+      DeviceType::Char => 'c',
+      DeviceType::Block => 'b',
+    }
+  }
+}
+
+/// A rule that allows access to devices, in the format of the v1 `devices`
+/// controller: `c 1:3 rwm`. `None` majors and minors match every device.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceRule {
+  pub kind: DeviceType,
+  pub major: Option<u64>,
+  pub minor: Option<u64>,
+  /// Any combination of `r` (read), `w` (write) and `m` (mknod).
+  pub access: String,
+}
+
+impl DeviceRule {
+  fn new(kind: DeviceType, major: Option<u64>, minor: Option<u64>, access: &str) -> Self {
+    DeviceRule {
+      kind,
+      major,
+      minor,
+      access: access.to_string(),
+    }
+  }
+
+  /// Parse a `--device-cgroup-rule` value like `c 10:229 rwm` or `b 8:* r`.
+  pub fn parse(rule: &str) -> Result<Self> {
+    let invalid = || DeviceError::InvalidRule(rule.to_string());
+    let fields: Vec<&str> = rule.split_whitespace().collect();
+    let (kind, numbers, access) = match fields.as_slice() {
+      [kind, numbers, access] => (*kind, *numbers, *access),
+      [kind, numbers] => (*kind, *numbers, "rwm"),
+      _ => return Err(Box::new(invalid())),
+    };
+
+    let kind = match kind {
+      "a" => DeviceType::All,
+      "c" => DeviceType::Char,
+      "b" => DeviceType::Block,
+      _ => return Err(Box::new(invalid())),
+    };
+    let mut numbers = numbers.splitn(2, ':');
+    let mut number = || match numbers.next() {
+      Some("*") => Ok(None),
+      Some(number) => number.parse().map(Some).map_err(|_| invalid()),
+      None => Err(invalid()),
+    };
+    let (major, minor) = (number()?, number()?);
+    if access.is_empty() || !access.chars().all(|c| "rwm".contains(c)) {
+      return Err(Box::new(invalid()));
+    }
+
+    Ok(DeviceRule::new(kind, major, minor, access))
+  }
+}
+
+impl fmt::Display for DeviceRule {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let number = |number: Option<u64>| match number {
+      Some(number) => number.to_string(),
+      None => "*".to_string(),
+    };
+    write!(
+      f,
+      "{} {}:{} {}",
+      self.kind.as_char(),
+      number(self.major),
+      number(self.minor),
+      self.access
+    )
+  }
+}
+
+/// A device node of the host that is exposed inside the container.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Device {
+  pub host_path: String,
+  pub container_path: String,
+  pub kind: DeviceType,
+  pub major: u64,
+  pub minor: u64,
+  pub mode: u32,
+  pub access: String,
+}
+
+impl Device {
+  /// Parse a `--device` value like `/dev/fuse`, `/dev/fuse:rwm` or
+  /// `/dev/sda:/dev/xvda:r`.
+  pub fn parse(device: &str) -> Result<Self> {
+    let mut parts: Vec<&str> = device.split(':').collect();
+    let access = match parts.last() {
+      Some(access) if parts.len() > 1 && access.chars().all(|c| "rwm".contains(c)) => {
+        parts.pop().unwrap()
+      }
+      _ => "rwm",
+    };
+    let (host_path, container_path) = match parts.as_slice() {
+      [host_path] => (*host_path, *host_path),
+      [host_path, container_path] => (*host_path, *container_path),
+      _ => return Err(Box::new(DeviceError::InvalidDevice(device.to_string()))),
+    };
+    if access.is_empty() || !container_path.starts_with('/') {
+      return Err(Box::new(DeviceError::InvalidDevice(device.to_string())));
+    }
+
+    let stat = stat(host_path)?;
+    let kind = match SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT {
+      SFlag::S_IFCHR => DeviceType::Char,
+      SFlag::S_IFBLK => DeviceType::Block,
+      _ => return Err(Box::new(DeviceError::NotADevice(host_path.to_string()))),
+    };
+
+    Ok(Device {
+      host_path: host_path.to_string(),
+      container_path: container_path.to_string(),
+      kind,
+      major: major(stat.st_rdev),
+      minor: minor(stat.st_rdev),
+      mode: stat.st_mode & 0o777,
+      access: access.to_string(),
+    })
+  }
+
+  fn rule(&self) -> DeviceRule {
+    DeviceRule::new(self.kind, Some(self.major), Some(self.minor), &self.access)
+  }
+}
+
+/// The rules every container gets, which cover the default device nodes. Any
+/// other device is denied.
+pub fn default_rules() -> Vec<DeviceRule> {
+  let mut rules = vec![
+    // Creating device nodes is fine, opening them is what the rules guard.
+    DeviceRule::new(DeviceType::Char, None, None, "m"),
+    DeviceRule::new(DeviceType::Block, None, None, "m"),
+  ];
+  for (_, major, minor) in DEFAULT_DEVICES {
+    rules.push(DeviceRule::new(
+      DeviceType::Char,
+      Some(*major),
+      Some(*minor),
+      "rwm",
+    ));
+  }
+  // /dev/ptmx and the pseudo terminals under /dev/pts.
+  rules.push(DeviceRule::new(DeviceType::Char, Some(5), Some(2), "rwm"));
+  rules.push(DeviceRule::new(DeviceType::Char, Some(136), None, "rwm"));
+  rules
+}
+
+/// Collect the requested devices and the complete list of device rules of
+/// the `run` subcommand.
+pub fn from_args(args: &clap::ArgMatches) -> Result<(Vec<Device>, Vec<DeviceRule>)> {
+  let mut devices = vec![];
+  let mut rules = default_rules();
+
+  if let Some(values) = args.values_of("device") {
+    for value in values {
+      let device = Device::parse(value)?;
+      rules.push(device.rule());
+      devices.push(device);
+    }
+  }
+  if let Some(values) = args.values_of("device-cgroup-rule") {
+    for value in values {
+      rules.push(DeviceRule::parse(value)?);
+    }
+  }
+
+  Ok((devices, rules))
+}
+
+/// Populate the `/dev` of the container with a fresh tmpfs holding the
+/// default device nodes and the requested ones. It's called inside the
+/// container after changing the root.
+pub fn setup_dev(devices: &[Device]) -> Result<()> {
+  const NONE: Option<&'static [u8]> = None;
+  fs::create_dir_all("/dev")?;
+  mount(
+    Some("tmpfs"),
+    "/dev",
+    Some("tmpfs"),
+    MsFlags::MS_NOSUID | MsFlags::MS_STRICTATIME,
+    Some("mode=755,size=65536k"),
+  )?;
+
+  // Don't let the umask strip the permissions of the nodes.
+  let old_umask = umask(Mode::empty());
+  for (path, major, minor) in DEFAULT_DEVICES {
+    mknod(
+      *path,
+      SFlag::S_IFCHR,
+      Mode::from_bits_truncate(0o666),
+      makedev(*major, *minor),
+    )?;
+  }
+  for device in devices {
+    let path = Path::new(&device.container_path);
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+    let kind = match device.kind {
+      DeviceType::Block => SFlag::S_IFBLK,
+      _ => SFlag::S_IFCHR,
+    };
+    mknod(
+      path,
+      kind,
+      Mode::from_bits_truncate(device.mode),
+      makedev(device.major, device.minor),
+    )?;
+  }
+  umask(old_umask);
+
+  fs::create_dir("/dev/pts")?;
+  mount(
+    Some("devpts"),
+    "/dev/pts",
+    Some("devpts"),
+    MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+    Some("newinstance,ptmxmode=0666,mode=0620"),
+  )?;
+  fs::create_dir("/dev/shm")?;
+  mount(
+    Some("shm"),
+    "/dev/shm",
+    Some("tmpfs"),
+    MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+    NONE,
+  )?;
+  for (target, link) in DEFAULT_LINKS {
+    symlink(target, link)?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_rules() {
+    let rule = DeviceRule::parse("c 10:229 rwm").unwrap();
+    assert_eq!(rule.to_string(), "c 10:229 rwm");
+    assert_eq!((rule.major, rule.minor), (Some(10), Some(229)));
+
+    let rule = DeviceRule::parse("b 8:* r").unwrap();
+    assert_eq!((rule.major, rule.minor), (Some(8), None));
+    assert_eq!(DeviceRule::parse("a *:*").unwrap().to_string(), "a *:* rwm");
+  }
+
+  #[test]
+  fn parse_numbers_bounds() {
+    let rule = DeviceRule::parse("c 18446744073709551615:0 r").unwrap();
+    assert_eq!(rule.major, Some(u64::MAX));
+    assert!(DeviceRule::parse("c 18446744073709551616:0 r").is_err());
+    assert!(DeviceRule::parse("c -1:0 r").is_err());
+  }
+
+  #[test]
+  fn parse_invalid_rules() {
+    for rule in &[
+      "",
+      "c",
+      "c 10",
+      "c 10: r",
+      "c 10:1:2 r",
+      "x 10:229 r",
+      "c 10:229 rx",
+      "c 10:229 r w",
+    ] {
+      assert!(DeviceRule::parse(rule).is_err(), "{}", rule);
+    }
+  }
+}
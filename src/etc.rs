@@ -0,0 +1,224 @@
+use crate::fs::get_container_path;
+use crate::network::NetworkMode;
+use crate::state::ContainerState;
+use crate::Result;
+use nix::mount::{mount, MsFlags};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+/// Files we generate for every container, and bind mount over the ones of
+/// the image.
+const FILES: &[&str] = &["hostname", "hosts", "resolv.conf"];
+
+const HOST_HOSTS: &str = "/etc/hosts";
+const HOST_RESOLV_CONF: &str = "/etc/resolv.conf";
+/// The resolv.conf of systemd-resolved with the upstream servers, for hosts
+/// that point to its local stub resolver.
+const RESOLVED_RESOLV_CONF: &str = "/run/systemd/resolve/resolv.conf";
+/// Public resolvers for containers when the host only has local ones.
+const DEFAULT_DNS: &[&str] = &["8.8.8.8", "8.8.4.4"];
+
+const LOCAL_HOSTS: &str = "127.0.0.1\tlocalhost
+::1\tlocalhost ip6-localhost ip6-loopback
+fe00::0\tip6-localnet
+ff00::0\tip6-mcastprefix
+ff02::1\tip6-allnodes
+ff02::2\tip6-allrouters
+";
+
+#[derive(Debug)]
+pub enum EtcError {
+  InvalidHostname(String),
+  InvalidHost(String),
+}
+
+impl fmt::Display for EtcError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      EtcError::InvalidHostname(hostname) => write!(f, "Invalid hostname: {}", hostname),
+      EtcError::InvalidHost(host) => {
+        write!(f, "Invalid host {}, expected <name>:<address>", host)
+      }
+    }
+  }
+}
+
+impl Error for EtcError {}
+
+/// An entry added to `/etc/hosts` with `--add-host`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExtraHost {
+  pub name: String,
+  pub address: IpAddr,
+}
+
+impl ExtraHost {
+  /// Parse a value like `db:10.5.0.3`. The address can be an IPv6 one, so
+  /// only the first colon separates it from the name.
+  pub fn parse(value: &str) -> Result<Self> {
+    let invalid = || Box::new(EtcError::InvalidHost(value.to_string()));
+    let mut parts = value.splitn(2, ':');
+    let name = parts.next().unwrap();
+    let address = parts.next().ok_or_else(invalid)?;
+    if !is_valid_hostname(name) {
+      return Err(invalid());
+    }
+    Ok(ExtraHost {
+      name: name.to_string(),
+      address: address.parse().map_err(|_| invalid())?,
+    })
+  }
+}
+
+/// How the names are resolved inside the container.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EtcConfig {
+  #[serde(default)]
+  pub hostname: String,
+  #[serde(default)]
+  pub extra_hosts: Vec<ExtraHost>,
+  /// Name servers replacing the ones of the host.
+  #[serde(default)]
+  pub dns: Vec<IpAddr>,
+  /// Search domains replacing the ones of the host.
+  #[serde(default)]
+  pub dns_search: Vec<String>,
+}
+
+fn is_valid_hostname(hostname: &str) -> bool {
+  !hostname.is_empty()
+    && hostname.len() <= 63
+    && !hostname.starts_with('-')
+    && hostname
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+}
+
+/// Get the name resolution options of the `run` subcommand. The hostname
+/// defaults to the short ID of the container.
+pub fn from_args(args: &clap::ArgMatches, id: &str) -> Result<EtcConfig> {
+  let hostname = args.value_of("hostname").unwrap_or(&id[..12]);
+  if !is_valid_hostname(hostname) {
+    return Err(Box::new(EtcError::InvalidHostname(hostname.to_string())));
+  }
+
+  let mut config = EtcConfig {
+    hostname: hostname.to_string(),
+    ..Default::default()
+  };
+  if let Some(values) = args.values_of("add-host") {
+    for value in values {
+      config.extra_hosts.push(ExtraHost::parse(value)?);
+    }
+  }
+  if let Some(values) = args.values_of("dns") {
+    for value in values {
+      config.dns.push(value.parse()?);
+    }
+  }
+  if let Some(values) = args.values_of("dns-search") {
+    config.dns_search.extend(values.map(String::from));
+  }
+  Ok(config)
+}
+
+fn hosts(state: &ContainerState) -> Result<String> {
+  let mut hosts = match state.network {
+    // The container sees the same addresses as the host.
+    NetworkMode::Host => fs::read_to_string(HOST_HOSTS)?,
+    _ => LOCAL_HOSTS.to_string(),
+  };
+  if let Some(endpoint) = &state.endpoint {
+    hosts += &format!("{}\t{}\n", endpoint.address, state.etc.hostname);
+  }
+  for host in &state.etc.extra_hosts {
+    hosts += &format!("{}\t{}\n", host.address, host.name);
+  }
+  Ok(hosts)
+}
+
+fn resolv_conf(state: &ContainerState) -> Result<String> {
+  let mut host = fs::read_to_string(HOST_RESOLV_CONF).unwrap_or_default();
+  if host.contains("nameserver 127.0.0.53") && Path::new(RESOLVED_RESOLV_CONF).exists() {
+    host = fs::read_to_string(RESOLVED_RESOLV_CONF)?;
+  }
+
+  // The loopback resolvers of the host are only reachable from its own
+  // network namespace.
+  let keep_loopback = state.network == NetworkMode::Host;
+  let mut servers = vec![];
+  let mut lines = vec![];
+  for line in host.lines() {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    match fields.as_slice() {
+      ["nameserver", server, ..] => match server.parse::<IpAddr>() {
+        Ok(server) if keep_loopback || !server.is_loopback() => servers.push(server),
+        _ => {}
+      },
+      ["search", ..] | ["domain", ..] if !state.etc.dns_search.is_empty() => {}
+      _ => lines.push(line.to_string()),
+    }
+  }
+
+  if !state.etc.dns.is_empty() {
+    servers = state.etc.dns.clone();
+  } else if servers.is_empty() {
+    servers = DEFAULT_DNS
+      .iter()
+      .map(|server| server.parse().unwrap())
+      .collect();
+  }
+  if !state.etc.dns_search.is_empty() {
+    lines.push(format!("search {}", state.etc.dns_search.join(" ")));
+  }
+  for server in servers {
+    lines.push(format!("nameserver {}", server));
+  }
+  Ok(lines.join("\n") + "\n")
+}
+
+/// Write the files of the container into its state directory. It's called
+/// once the network of the container is configured, for its address.
+pub fn write_files(state: &ContainerState) -> Result<()> {
+  let path = get_container_path(&state.id);
+  fs::create_dir_all(&path)?;
+  fs::write(path.join("hostname"), format!("{}\n", state.etc.hostname))?;
+  fs::write(path.join("hosts"), hosts(state)?)?;
+  fs::write(path.join("resolv.conf"), resolv_conf(state)?)?;
+  Ok(())
+}
+
+/// Bind mount the generated files over the ones of the root file system. It's
+/// called inside the container before changing the root.
+pub fn mount_files(state: &ContainerState) -> Result<()> {
+  const NONE: Option<&'static [u8]> = None;
+  let source = get_container_path(&state.id);
+  let etc = state.rootfs.join("etc");
+  fs::create_dir_all(&etc)?;
+
+  for file in FILES {
+    let target = etc.join(file);
+    // A link would be followed out of the root file system, e.g. to the stub
+    // resolver config of systemd-resolved. Replace it with a plain file.
+    if let Ok(metadata) = fs::symlink_metadata(&target) {
+      if metadata.file_type().is_symlink() {
+        fs::remove_file(&target)?;
+      }
+    }
+    if !target.exists() {
+      fs::write(&target, "")?;
+    }
+    mount(
+      Some(&source.join(file)),
+      &target,
+      NONE,
+      MsFlags::MS_BIND,
+      NONE,
+    )?;
+  }
+  Ok(())
+}
@@ -43,6 +43,11 @@ fn untar(image: &str, container_id: &str) -> PathBuf {
     if image_path.exists() && image_path.is_dir() {
       for entry in fs::read_dir(image_path).unwrap() {
         let entry = entry.unwrap();
+        // The manifest and the configuration of the image are kept next to
+        // the layers.
+        if !entry.file_name().to_string_lossy().ends_with(".tar.gz") {
+          continue;
+        }
         untar_single(entry.path().to_str().unwrap(), &file_system_path);
       }
     } else {
@@ -91,3 +96,33 @@ pub fn get_image_path(image: &str) -> PathBuf {
   path.push(&image.replace("/", "_"));
   path
 }
+
+pub fn get_containers_path() -> PathBuf {
+  let mut path = get_file_system_root_path();
+  path.push("containers");
+  path
+}
+
+pub fn get_container_path(container_id: &str) -> PathBuf {
+  let mut path = get_containers_path();
+  path.push(container_id);
+  path
+}
+
+pub fn get_networks_path() -> PathBuf {
+  let mut path = get_file_system_root_path();
+  path.push("networks");
+  path
+}
+
+pub fn get_network_path(name: &str) -> PathBuf {
+  let mut path = get_networks_path();
+  path.push(name);
+  path
+}
+
+pub fn get_ports_path() -> PathBuf {
+  let mut path = get_file_system_root_path();
+  path.push("ports");
+  path
+}
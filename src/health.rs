@@ -0,0 +1,493 @@
+use crate::cgroups;
+use crate::fs::get_container_path;
+use crate::images;
+use crate::init;
+use crate::logs::time;
+use crate::state::{ContainerState, Status};
+use crate::Result;
+use nix::errno::Errno;
+use nix::fcntl::{open, OFlag};
+use nix::libc;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sched::{setns, CloneFlags};
+use nix::sys::signal::{kill, sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::stat::Mode;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{chdir, chroot, close, dup2, fchdir, fork, pipe2, read, write};
+use nix::unistd::{setpgid, ForkResult, Pid};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::ffi::CString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+const HEALTH_FILE: &str = "health.json";
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_RETRIES: u32 = 3;
+/// How many of the last probes are kept.
+const MAX_PROBES: usize = 5;
+/// How much of the output of a probe is kept.
+const MAX_OUTPUT_SIZE: usize = 4096;
+/// How often the monitor looks whether the container exited, and the probe
+/// whether it's done.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// The namespaces a probe joins, the mount namespace last since the others
+/// are opened through the /proc of the host.
+const NAMESPACES: &[&str] = &["ipc", "uts", "net", "pid", "cgroup", "mnt"];
+
+#[derive(Debug)]
+pub enum HealthError {
+  InvalidOption(String),
+}
+
+impl fmt::Display for HealthError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      HealthError::InvalidOption(option) => write!(f, "Invalid healthcheck option: {}", option),
+    }
+  }
+}
+
+impl Error for HealthError {}
+
+/// A healthcheck, in the format of the image configuration. Zero durations
+/// and retries mean the defaults.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "PascalCase")]
+pub struct HealthConfig {
+  /// `["CMD", args...]`, `["CMD-SHELL", command]` or `["NONE"]`.
+  pub test: Vec<String>,
+  /// Time between the probes, in nanoseconds.
+  pub interval: u64,
+  /// Time a probe gets before it's considered failed, in nanoseconds.
+  pub timeout: u64,
+  /// Time the container gets to start before the failures count, in
+  /// nanoseconds.
+  pub start_period: u64,
+  /// Consecutive failures that make the container unhealthy.
+  pub retries: u32,
+}
+
+impl HealthConfig {
+  /// The command of the probe, `None` if the healthcheck is disabled.
+  fn command(&self) -> Option<Vec<String>> {
+    match self.test.split_first() {
+      Some((kind, args)) if kind == "CMD" && !args.is_empty() => Some(args.to_vec()),
+      Some((kind, args)) if kind == "CMD-SHELL" && !args.is_empty() => Some(vec![
+        "/bin/sh".to_string(),
+        "-c".to_string(),
+        args.join(" "),
+      ]),
+      _ => None,
+    }
+  }
+
+  fn interval(&self) -> Duration {
+    or_default(self.interval, DEFAULT_INTERVAL)
+  }
+
+  fn timeout(&self) -> Duration {
+    or_default(self.timeout, DEFAULT_TIMEOUT)
+  }
+
+  fn start_period(&self) -> Duration {
+    Duration::from_nanos(self.start_period)
+  }
+
+  fn retries(&self) -> u32 {
+    if self.retries == 0 {
+      DEFAULT_RETRIES
+    } else {
+      self.retries
+    }
+  }
+}
+
+fn or_default(nanos: u64, default: Duration) -> Duration {
+  if nanos == 0 {
+    default
+  } else {
+    Duration::from_nanos(nanos)
+  }
+}
+
+/// The healthcheck of the container, from the image and the `run`
+/// subcommand arguments on top. `None` if it has none, or it's disabled.
+pub fn from_args(args: &clap::ArgMatches) -> Result<Option<HealthConfig>> {
+  let mut config = images::load_config(args.value_of("image").unwrap())?
+    .config
+    .healthcheck
+    .unwrap_or_default();
+  if let Some(command) = args.value_of("health-cmd") {
+    config.test = vec!["CMD-SHELL".to_string(), command.to_string()];
+  }
+  if let Some(interval) = parse_duration_arg(args, "health-interval")? {
+    config.interval = interval;
+  }
+  if let Some(timeout) = parse_duration_arg(args, "health-timeout")? {
+    config.timeout = timeout;
+  }
+  if let Some(start_period) = parse_duration_arg(args, "health-start-period")? {
+    config.start_period = start_period;
+  }
+  if let Some(retries) = args.value_of("health-retries") {
+    config.retries = retries
+      .parse()
+      .map_err(|_| HealthError::InvalidOption(format!("--health-retries {}", retries)))?;
+  }
+
+  Ok(config.command().map(|_| config))
+}
+
+/// Parse a duration argument into nanoseconds.
+fn parse_duration_arg(args: &clap::ArgMatches, name: &str) -> Result<Option<u64>> {
+  match args.value_of(name) {
+    Some(value) => match time::parse_duration(value) {
+      Some(duration) => Ok(Some(duration.as_nanos() as u64)),
+      None => Err(Box::new(HealthError::InvalidOption(format!(
+        "--{} {}",
+        name, value
+      )))),
+    },
+    None => Ok(None),
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+  /// No probe passed yet.
+  Starting,
+  Healthy,
+  /// The last probes failed, as many as the retries.
+  Unhealthy,
+}
+
+/// The result of a probe.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Probe {
+  /// When the probe started and ended, in RFC 3339.
+  pub start: String,
+  pub end: String,
+  /// Exit code of the command, `-1` if it couldn't run or timed out.
+  pub exit_code: i32,
+  pub output: String,
+}
+
+/// The health of the current run of a container. Its shim keeps it next to
+/// the state of the container.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Health {
+  pub status: HealthStatus,
+  /// How many probes failed in a row.
+  pub failing_streak: u32,
+  /// The last probes, the oldest first.
+  pub log: Vec<Probe>,
+}
+
+impl Health {
+  fn new() -> Self {
+    Health {
+      status: HealthStatus::Starting,
+      failing_streak: 0,
+      log: vec![],
+    }
+  }
+
+  /// Load the health of the container, if it has a healthcheck.
+  pub fn load(id: &str) -> Result<Option<Self>> {
+    let path = get_container_path(id).join(HEALTH_FILE);
+    if !path.exists() {
+      return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice(&fs::read(path)?)?))
+  }
+
+  fn save(&self, id: &str) -> Result<()> {
+    let path = get_container_path(id);
+    // Written like the state, so that readers never see a half written one.
+    let tmp_path = path.join(format!("{}.tmp", HEALTH_FILE));
+    fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+    fs::rename(tmp_path, path.join(HEALTH_FILE))?;
+    Ok(())
+  }
+
+  /// Count the probe. The failures during the start period don't count,
+  /// unless a probe passed already.
+  fn record(&mut self, probe: Probe, retries: u32, starting: bool) {
+    if probe.exit_code == 0 {
+      self.status = HealthStatus::Healthy;
+      self.failing_streak = 0;
+    } else if !(starting && self.status == HealthStatus::Starting) {
+      self.failing_streak += 1;
+      if self.failing_streak >= retries {
+        self.status = HealthStatus::Unhealthy;
+      }
+    }
+    self.log.push(probe);
+    self.log.drain(..self.log.len().saturating_sub(MAX_PROBES));
+  }
+}
+
+/// Runs the healthcheck of a container from a thread of its shim, until the
+/// container exits.
+pub struct Monitor {
+  exited: Arc<AtomicBool>,
+  thread: JoinHandle<()>,
+}
+
+impl Monitor {
+  /// Start probing the container, if it has a healthcheck.
+  pub fn start(state: &ContainerState, pid: Pid) -> Option<Self> {
+    let config = state.healthcheck.clone()?;
+    let command = config.command()?;
+    let exited = Arc::new(AtomicBool::new(false));
+    let monitor_exited = Arc::clone(&exited);
+    let id = state.id.clone();
+    let thread = thread::spawn(move || monitor(&id, pid, &config, &command, &monitor_exited));
+    Some(Monitor { exited, thread })
+  }
+
+  /// Stop probing once the container exited.
+  pub fn stop(self) {
+    self.exited.store(true, Ordering::SeqCst);
+    let _ = self.thread.join();
+  }
+}
+
+fn monitor(id: &str, pid: Pid, config: &HealthConfig, command: &[String], exited: &AtomicBool) {
+  let mut health = Health::new();
+  if let Err(err) = health.save(id) {
+    warn!("Failed to save the health of the container: {}", err);
+  }
+
+  let started = Instant::now();
+  loop {
+    let next = Instant::now() + config.interval();
+    while Instant::now() < next {
+      if exited.load(Ordering::SeqCst) {
+        return;
+      }
+      thread::sleep(POLL_INTERVAL);
+    }
+    // The processes of a paused container can't answer.
+    if let Ok(state) = ContainerState::load(id) {
+      if state.status == Status::Paused {
+        continue;
+      }
+    }
+
+    let start = SystemTime::now();
+    let (exit_code, output) = match probe(id, pid, command, config.timeout()) {
+      Ok(result) => result,
+      Err(err) => (-1, format!("Failed to run the healthcheck: {}", err)),
+    };
+    // The probe got killed together with the container.
+    if exited.load(Ordering::SeqCst) {
+      return;
+    }
+    let probe = Probe {
+      start: time::format(start),
+      end: time::format(SystemTime::now()),
+      exit_code,
+      output,
+    };
+    health.record(
+      probe,
+      config.retries(),
+      started.elapsed() < config.start_period(),
+    );
+    if let Err(err) = health.save(id) {
+      warn!("Failed to save the health of the container: {}", err);
+    }
+  }
+}
+
+/// Run the command of the probe in the namespaces, the cgroup and the root of
+/// the container. Returns its exit code and output.
+fn probe(id: &str, pid: Pid, command: &[String], timeout: Duration) -> Result<(i32, String)> {
+  // Everything the probe needs is prepared before the fork, the threads of
+  // the shim might hold locks.
+  let proc_path = format!("/proc/{}", pid);
+  let mut namespaces = vec![];
+  for namespace in NAMESPACES {
+    namespaces.push(File::open(format!("{}/ns/{}", proc_path, namespace))?);
+  }
+  let root = File::open(format!("{}/root", proc_path))?;
+  let args: Vec<CString> = command
+    .iter()
+    .map(|arg| CString::new(arg.as_str()))
+    .collect::<std::result::Result<_, _>>()?;
+  // The null terminated array execvp takes, nix would allocate it.
+  let mut argv: Vec<*const libc::c_char> = args.iter().map(|arg| arg.as_ptr()).collect();
+  argv.push(ptr::null());
+  let null = open("/dev/null", OFlag::O_RDWR | OFlag::O_CLOEXEC, Mode::empty())?;
+  let (output_read, output_write) = pipe2(OFlag::O_CLOEXEC)?;
+  let (sync_read, sync_write) = pipe2(OFlag::O_CLOEXEC)?;
+
+  let child = match fork()? {
+    ForkResult::Child => {
+      let code = run_probe(&namespaces, &root, &argv, null, output_write, sync_read);
+      unsafe { libc::_exit(code) };
+    }
+    ForkResult::Parent { child } => child,
+  };
+  close(null)?;
+  close(output_write)?;
+  close(sync_read)?;
+  drop(namespaces);
+  drop(root);
+
+  // The probe counts against the limits of the container.
+  if let Err(err) = cgroups::new(id).and_then(|cgroup| cgroup.add_task(child)) {
+    warn!("Failed to move the healthcheck into the cgroup: {}", err);
+  }
+  write(sync_write, &[0])?;
+  close(sync_write)?;
+
+  let mut output = unsafe { File::from_raw_fd(output_read) };
+  let mut data = vec![];
+  let mut output_open = true;
+  let deadline = Instant::now() + timeout;
+  let status = loop {
+    match waitpid(child, Some(WaitPidFlag::WNOHANG))? {
+      WaitStatus::StillAlive => {}
+      status => break Some(status),
+    }
+    if Instant::now() >= deadline {
+      let _ = kill(child, Signal::SIGTERM);
+      let _ = waitpid(child, None);
+      break None;
+    }
+    if output_open {
+      output_open = read_output(&mut output, &mut data, POLL_INTERVAL)? != Some(0);
+    } else {
+      thread::sleep(Duration::from_millis(10));
+    }
+  };
+  // Whatever the command wrote before it exited.
+  if output_open {
+    while let Some(len) = read_output(&mut output, &mut data, Duration::default())? {
+      if len == 0 {
+        break;
+      }
+    }
+  }
+
+  let output = String::from_utf8_lossy(&data).into_owned();
+  match status.and_then(init::exit_code) {
+    Some(code) => Ok((code, output)),
+    None => Ok((-1, format!("Health check exceeded timeout ({:?})", timeout))),
+  }
+}
+
+/// Read the output of the probe if there is some within the timeout, keeping
+/// the start of it. Returns how much was read, zero once the output is closed.
+fn read_output(output: &mut File, data: &mut Vec<u8>, timeout: Duration) -> Result<Option<usize>> {
+  let mut fds = [PollFd::new(output.as_raw_fd(), PollFlags::POLLIN)];
+  match poll(&mut fds, timeout.as_millis() as i32) {
+    Ok(0) | Err(nix::Error::Sys(Errno::EINTR)) => return Ok(None),
+    Ok(_) => {}
+    Err(err) => return Err(Box::new(err)),
+  }
+  let mut buf = [0; MAX_OUTPUT_SIZE];
+  let len = output.read(&mut buf)?;
+  let kept = len.min(MAX_OUTPUT_SIZE - data.len());
+  data.extend_from_slice(&buf[..kept]);
+  Ok(Some(len))
+}
+
+/// The command of the probe, once it runs.
+static COMMAND: AtomicI32 = AtomicI32::new(0);
+
+/// Kill the command of the probe, and every process it started, once the
+/// probe timed out.
+extern "C" fn kill_command(_: libc::c_int) {
+  let command = COMMAND.load(Ordering::SeqCst);
+  if command > 0 {
+    unsafe { libc::kill(-command, libc::SIGKILL) };
+  }
+}
+
+/// The forked process of a probe. It joins the container, and runs the
+/// command as its child, in the PID namespace of the container.
+fn run_probe(
+  namespaces: &[File],
+  root: &File,
+  argv: &[*const libc::c_char],
+  null: RawFd,
+  output: RawFd,
+  sync_read: RawFd,
+) -> i32 {
+  // The timeout can't kill the command before it runs, SIGTERM is held back
+  // until then.
+  let mut terminate = SigSet::empty();
+  terminate.add(Signal::SIGTERM);
+  if terminate.thread_block().is_err() {
+    return 1;
+  }
+  let handler = SigAction::new(
+    SigHandler::Handler(kill_command),
+    SaFlags::empty(),
+    SigSet::empty(),
+  );
+  if unsafe { sigaction(Signal::SIGTERM, &handler) }.is_err() {
+    return 1;
+  }
+  // Wait until the parent moves us into the cgroup.
+  let mut buf = [0; 1];
+  if read(sync_read, &mut buf) != Ok(1) {
+    return 1;
+  }
+  for namespace in namespaces {
+    if setns(namespace.as_raw_fd(), CloneFlags::empty()).is_err() {
+      return 1;
+    }
+  }
+  if fchdir(root.as_raw_fd()).is_err() || chroot(".").is_err() || chdir("/").is_err() {
+    return 1;
+  }
+
+  match fork() {
+    Ok(ForkResult::Child) => {
+      // The command and its children form a group, that gets killed once
+      // the probe times out.
+      let _ = setpgid(Pid::from_raw(0), Pid::from_raw(0));
+      let default = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+      let _ = unsafe { sigaction(Signal::SIGTERM, &default) };
+      let _ = terminate.thread_unblock();
+      let _ = dup2(null, 0);
+      let _ = dup2(output, 1);
+      let _ = dup2(output, 2);
+      unsafe {
+        libc::execvp(argv[0], argv.as_ptr());
+        libc::_exit(127);
+      }
+    }
+    Ok(ForkResult::Parent { child }) => {
+      // Either of us may run first.
+      let _ = setpgid(child, child);
+      COMMAND.store(i32::from(child), Ordering::SeqCst);
+      // A timeout that came meanwhile kills the command now.
+      let _ = terminate.thread_unblock();
+      let _ = close(output);
+      loop {
+        match waitpid(child, None) {
+          Ok(status) => return init::exit_code(status).unwrap_or(1),
+          // Interrupted by the timeout, the command is being killed.
+          Err(nix::Error::Sys(Errno::EINTR)) => {}
+          Err(_) => return 1,
+        }
+      }
+    }
+    Err(_) => 1,
+  }
+}
@@ -1,9 +1,43 @@
 use crate::fs::{get_image_path, get_images_path};
+use crate::health::HealthConfig;
 use crate::Result;
+use serde::Deserialize;
 use std::fs;
 use std::io;
 use std::io::Write;
 
+/// The manifest and the configuration of a pulled image are kept next to its
+/// layers.
+pub const MANIFEST_FILE: &str = "manifest.json";
+pub const CONFIG_FILE: &str = "config.json";
+
+/// The configuration of an image, as far as we use it. Docker images keep
+/// the settings of the containers under `config`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ImageConfig {
+  #[serde(default)]
+  pub config: ContainerConfig,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerConfig {
+  /// The signal that stops the command of the image gracefully.
+  pub stop_signal: Option<String>,
+  /// How to check that the command of the image works.
+  pub healthcheck: Option<HealthConfig>,
+}
+
+/// Load the configuration of an image. Tarballs, and the images pulled
+/// before it was kept, don't have one.
+pub fn load_config(image: &str) -> Result<ImageConfig> {
+  let path = get_image_path(image).join(CONFIG_FILE);
+  if image.contains(".tar") || !path.exists() {
+    return Ok(ImageConfig::default());
+  }
+  Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
 /// Main entry for the images subcommand
 /// TODO: Move all the subcommands into their own directory.
 pub fn images(args: &clap::ArgMatches<'static>) -> Result<()> {
@@ -0,0 +1,106 @@
+use crate::Result;
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::signal::{kill, SigSet, SigmaskHow, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{execvp, fork, getpid, setpgid, tcsetpgrp, ForkResult, Pid};
+use std::ffi::CStr;
+
+const STDIN: i32 = 0;
+
+/// Signals that are raised by a fault of the process itself, they can't be
+/// waited for.
+const SYNCHRONOUS_SIGNALS: &[Signal] = &[
+  Signal::SIGFPE,
+  Signal::SIGILL,
+  Signal::SIGSEGV,
+  Signal::SIGBUS,
+  Signal::SIGABRT,
+  Signal::SIGTRAP,
+  Signal::SIGSYS,
+];
+
+/// The signals the init waits for. The kernel drops the signals without a
+/// handler that are sent to the PID 1 of a namespace, but not the blocked
+/// ones.
+fn blocked_signals() -> SigSet {
+  let mut signals = SigSet::empty();
+  for signal in Signal::iterator() {
+    if signal != Signal::SIGKILL
+      && signal != Signal::SIGSTOP
+      && !SYNCHRONOUS_SIGNALS.contains(&signal)
+    {
+      signals.add(signal);
+    }
+  }
+  signals
+}
+
+/// The exit code of a process, 128 plus the signal if it got killed.
+pub fn exit_code(status: WaitStatus) -> Option<i32> {
+  match status {
+    WaitStatus::Exited(_, code) => Some(code),
+    WaitStatus::Signaled(_, signal, _) => Some(128 + signal as i32),
+    _ => None,
+  }
+}
+
+/// Run the command as the child of a minimal init, as PID 1 of the
+/// container. The init forwards the signals it gets to the command, and
+/// reaps the orphaned processes of the container. It exits with the exit
+/// code of the command, the kernel kills the processes that are left then.
+pub fn run(argv: &[&CStr], tty: bool) -> Result<i32> {
+  let signals = blocked_signals();
+  let previous_mask = signals.thread_swap_mask(SigmaskHow::SIG_BLOCK)?;
+
+  let command = match fork()? {
+    ForkResult::Child => {
+      // Give the command a process group of its own, in the foreground of the
+      // terminal for job control.
+      let _ = setpgid(Pid::from_raw(0), Pid::from_raw(0));
+      if tty {
+        let _ = tcsetpgrp(STDIN, getpid());
+      }
+      let _ = previous_mask.thread_set_mask();
+      let err = execvp(argv[0], argv).unwrap_err();
+      error!(
+        "Failed to execute the command inside the container: {}",
+        err
+      );
+      unsafe { libc::_exit(127) };
+    }
+    ForkResult::Parent { child } => child,
+  };
+
+  loop {
+    match signals.wait()? {
+      Signal::SIGCHLD => {}
+      // Only meant for the init, if it touches the terminal.
+      Signal::SIGTTIN | Signal::SIGTTOU => continue,
+      signal => {
+        if let Err(err) = kill(command, signal) {
+          debug!(
+            "Failed to forward {} to the command: {}",
+            signal.as_str(),
+            err
+          );
+        }
+        continue;
+      }
+    }
+
+    // Several children may have exited for a single SIGCHLD.
+    loop {
+      match waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG)) {
+        Ok(WaitStatus::StillAlive) | Err(nix::Error::Sys(Errno::ECHILD)) => break,
+        Ok(status) if status.pid() == Some(command) => {
+          if let Some(code) = exit_code(status) {
+            return Ok(code);
+          }
+        }
+        Ok(_) => {}
+        Err(err) => return Err(Box::new(err)),
+      }
+    }
+  }
+}
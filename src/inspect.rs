@@ -0,0 +1,239 @@
+use crate::cgroups;
+use crate::fs::get_image_path;
+use crate::health::Health;
+use crate::images::{CONFIG_FILE, MANIFEST_FILE};
+use crate::state::{self, ContainerState, StateError, Status};
+use crate::Result;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum InspectError {
+  NotFound(String),
+}
+
+impl fmt::Display for InspectError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      InspectError::NotFound(name) => write!(f, "No such container or image: {}", name),
+    }
+  }
+}
+
+impl Error for InspectError {}
+
+/// A mount of a running container, as the container sees it.
+#[derive(Debug, Serialize)]
+struct Mount {
+  destination: String,
+  #[serde(rename = "type")]
+  kind: String,
+  source: String,
+  /// The directory of the source file system that is mounted.
+  root: String,
+  options: String,
+}
+
+/// A layer of an image.
+#[derive(Debug, Serialize)]
+struct Layer {
+  digest: Option<String>,
+  /// Size of the compressed layer, as the manifest tells it.
+  size: Option<u64>,
+  /// The layer tarball on disk, if it's there.
+  path: Option<PathBuf>,
+  file_size: Option<u64>,
+}
+
+/// Main entry for the inspect subcommand. Prints what a container or an
+/// image is made of as JSON. Containers are looked up first, by their ID or
+/// a prefix of it.
+pub fn inspect(args: &clap::ArgMatches<'static>) -> Result<()> {
+  let name = args.value_of("name").unwrap();
+  let info = match state::find(name) {
+    Ok(state) => inspect_container(&state)?,
+    Err(_) if get_image_path(name).is_dir() => inspect_image(name)?,
+    Err(err) => match err.downcast_ref::<StateError>() {
+      Some(StateError::NotFound(_)) => {
+        return Err(Box::new(InspectError::NotFound(name.to_string())))
+      }
+      _ => return Err(err),
+    },
+  };
+  println!("{}", serde_json::to_string_pretty(&info)?);
+  Ok(())
+}
+
+fn inspect_container(state: &ContainerState) -> Result<Value> {
+  let running = state.is_running();
+  let (pid, cgroup, mounts) = if running {
+    let paths = cgroups::new(&state.id)?.paths();
+    (state.pid, Some(paths), read_mounts(state.pid)?)
+  } else {
+    (0, None, vec![])
+  };
+
+  Ok(json!({
+    "id": state.id,
+    "created": state.created,
+    "image": state.image,
+    "command": state.command,
+    "state": {
+      "status": state.status,
+      "running": running,
+      "paused": running && state.status == Status::Paused,
+      "restarting": state.is_restarting(),
+      "started": state.started,
+      "finished": state.finished,
+      "exit_status": state.exit_status,
+      "restart_count": state.restart_count,
+      "manually_stopped": state.manually_stopped,
+      "health": Health::load(&state.id)?,
+    },
+    "config": {
+      "tty": state.tty,
+      "interactive": state.interactive,
+      "init": state.init,
+      "auto_remove": state.auto_remove,
+      "stop_signal": state.stop_signal,
+      "restart_policy": state.restart_policy,
+      "healthcheck": state.healthcheck,
+      "log": state.log,
+      "rlimits": state.rlimits,
+      "resources": state.resources,
+      "devices": state.devices,
+      "device_rules": state.device_rules,
+    },
+    "pid": pid,
+    "cgroup": cgroup,
+    "mounts": mounts,
+    "network": {
+      "mode": state.network,
+      "ip": state.ip,
+      "ports": state.ports,
+      "endpoint": state.endpoint,
+      "hostname": state.etc.hostname,
+      "extra_hosts": state.etc.extra_hosts,
+      "dns": state.etc.dns,
+      "dns_search": state.etc.dns_search,
+    },
+    "rootfs": state.rootfs,
+  }))
+}
+
+/// Read the mounts of a process, relative to its root. The kernel leaves out
+/// the mounts of the host outside the root of the container.
+fn read_mounts(pid: i32) -> Result<Vec<Mount>> {
+  let mut mounts = vec![];
+  for line in fs::read_to_string(format!("/proc/{}/mountinfo", pid))?.lines() {
+    // The optional fields before the separator vary in number.
+    let mut parts = line.splitn(2, " - ");
+    let fields: Vec<&str> = parts.next().unwrap_or("").split(' ').collect();
+    let source: Vec<&str> = parts.next().unwrap_or("").split(' ').collect();
+    if fields.len() < 6 || source.len() < 2 {
+      continue;
+    }
+    mounts.push(Mount {
+      destination: unescape(fields[4]),
+      kind: source[0].to_string(),
+      source: unescape(source[1]),
+      root: unescape(fields[3]),
+      options: fields[5].to_string(),
+    });
+  }
+  Ok(mounts)
+}
+
+/// Undo the octal escapes of the spaces and the like in mountinfo paths.
+fn unescape(value: &str) -> String {
+  let mut bytes = vec![];
+  let mut rest = value.as_bytes();
+  while let Some((&byte, tail)) = rest.split_first() {
+    match (byte, tail.get(..3)) {
+      (b'\\', Some(octal)) if octal.iter().all(|digit| (b'0'..=b'7').contains(digit)) => {
+        bytes.push(
+          octal
+            .iter()
+            .fold(0u8, |value, digit| value * 8 + (digit - b'0')),
+        );
+        rest = &tail[3..];
+      }
+      _ => {
+        bytes.push(byte);
+        rest = tail;
+      }
+    }
+  }
+  String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn inspect_image(name: &str) -> Result<Value> {
+  let path = get_image_path(name);
+  let manifest = read_json(&path.join(MANIFEST_FILE))?;
+  let config = read_json(&path.join(CONFIG_FILE))?;
+
+  let layers = match manifest
+    .as_ref()
+    .and_then(|manifest| manifest["layers"].as_array())
+  {
+    Some(layers) => layers
+      .iter()
+      .map(|layer| {
+        let digest = layer["digest"].as_str().map(String::from);
+        // The layers are named after the hex of their digest.
+        let file = digest
+          .as_ref()
+          .map(|digest| path.join(format!("{}.tar.gz", digest.trim_start_matches("sha256:"))))
+          .filter(|file| file.exists());
+        Ok(Layer {
+          digest,
+          size: layer["size"].as_u64(),
+          file_size: file_size(file.as_deref())?,
+          path: file,
+        })
+      })
+      .collect::<Result<_>>()?,
+    // Images pulled before the manifest was kept only have their tarballs.
+    None => {
+      let mut layers = vec![];
+      for entry in fs::read_dir(&path)? {
+        let file = entry?.path();
+        if file.to_string_lossy().ends_with(".tar.gz") {
+          layers.push(Layer {
+            digest: None,
+            size: None,
+            file_size: file_size(Some(&file))?,
+            path: Some(file),
+          });
+        }
+      }
+      layers
+    }
+  };
+
+  Ok(json!({
+    "name": name,
+    "path": path,
+    "manifest": manifest,
+    "config": config,
+    "layers": layers,
+  }))
+}
+
+fn read_json(path: &Path) -> Result<Option<Value>> {
+  if !path.exists() {
+    return Ok(None);
+  }
+  Ok(Some(serde_json::from_slice(&fs::read(path)?)?))
+}
+
+fn file_size(path: Option<&Path>) -> Result<Option<u64>> {
+  match path {
+    Some(path) => Ok(Some(fs::metadata(path)?.len())),
+    None => Ok(None),
+  }
+}
@@ -0,0 +1,85 @@
+use super::{json_file, time, DriverKind, LogEntry, LogError, Stream};
+use crate::state::{self, ContainerState, Status};
+use crate::Result;
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// How often new entries are looked for with `--follow`.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Main entry for the logs subcommand. Prints the output of a container, and
+/// keeps printing it until the container exits with `--follow`.
+pub fn logs(args: &clap::ArgMatches<'static>) -> Result<()> {
+  let state = state::find(args.value_of("id").unwrap())?;
+  if state.log.driver != DriverKind::JsonFile {
+    return Err(Box::new(LogError::NotReadable(
+      state.log.driver.to_string(),
+    )));
+  }
+  let since = match args.value_of("since") {
+    Some(since) => Some(time::parse_since(since)?),
+    None => None,
+  };
+  let tail = match args.value_of("tail") {
+    None | Some("all") => None,
+    Some(tail) => Some(
+      tail
+        .parse::<usize>()
+        .map_err(|_| LogError::InvalidOption(format!("--tail {}", tail)))?,
+    ),
+  };
+  let timestamps = args.is_present("timestamps");
+
+  let (mut entries, mut follower) = json_file::read(&state.id, &state.log)?;
+  if let Some(since) = since {
+    entries.retain(|entry| is_since(entry, since));
+  }
+  if let Some(tail) = tail {
+    entries.drain(..entries.len().saturating_sub(tail));
+  }
+  print(&entries, timestamps)?;
+
+  if args.is_present("follow") {
+    loop {
+      // The shim records the exit once the whole output is logged, nothing
+      // is logged anymore once it's gone. Check before reading, so that the
+      // last lines are read once the container is gone.
+      let running = match ContainerState::load(&state.id) {
+        Ok(state) => state.status != Status::Exited && state.has_shim(),
+        Err(_) => false,
+      };
+      print(&follower.read()?, timestamps)?;
+      if !running {
+        break;
+      }
+      thread::sleep(FOLLOW_INTERVAL);
+    }
+  }
+  Ok(())
+}
+
+fn is_since(entry: &LogEntry, since: SystemTime) -> bool {
+  match time::parse_rfc3339(&entry.time) {
+    Some(time) => time >= since,
+    None => true,
+  }
+}
+
+/// Print the entries to the stream they were written to.
+fn print(entries: &[LogEntry], timestamps: bool) -> Result<()> {
+  let (stdout, stderr) = (io::stdout(), io::stderr());
+  let (mut stdout, mut stderr) = (stdout.lock(), stderr.lock());
+  for entry in entries {
+    let output: &mut dyn Write = match entry.stream {
+      Stream::Stdout => &mut stdout,
+      Stream::Stderr => &mut stderr,
+    };
+    if timestamps {
+      write!(output, "{} ", entry.time)?;
+    }
+    output.write_all(entry.log.as_bytes())?;
+  }
+  stdout.flush()?;
+  Ok(())
+}
@@ -0,0 +1,161 @@
+use super::{LogConfig, LogDriver, LogEntry};
+use crate::fs::get_container_path;
+use crate::Result;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+
+/// The current log file of the container, the rotated ones get a `.1`, `.2`,
+/// ... suffix, the higher the older.
+pub fn log_path(id: &str) -> PathBuf {
+  get_container_path(id).join(format!("{}-json.log", id))
+}
+
+fn rotated_path(id: &str, index: u32) -> PathBuf {
+  match index {
+    0 => log_path(id),
+    _ => get_container_path(id).join(format!("{}-json.log.{}", id, index)),
+  }
+}
+
+/// Writes the entries as JSON lines, and rotates the file once it grows
+/// past the maximum size.
+pub struct JsonFile {
+  id: String,
+  config: LogConfig,
+  file: File,
+  size: u64,
+}
+
+impl JsonFile {
+  pub fn open(id: &str, config: &LogConfig) -> Result<Self> {
+    let path = log_path(id);
+    fs::create_dir_all(path.parent().unwrap())?;
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(JsonFile {
+      id: id.to_string(),
+      config: config.clone(),
+      size: file.metadata()?.len(),
+      file,
+    })
+  }
+
+  /// Shift the older files by one, dropping the oldest, and start an empty
+  /// current file.
+  fn rotate(&mut self) -> Result<()> {
+    for index in (1..self.config.max_files).rev() {
+      let path = rotated_path(&self.id, index - 1);
+      if path.exists() {
+        fs::rename(path, rotated_path(&self.id, index))?;
+      }
+    }
+    self.file = OpenOptions::new()
+      .create(true)
+      .write(true)
+      .truncate(true)
+      .open(log_path(&self.id))?;
+    self.size = 0;
+    Ok(())
+  }
+}
+
+impl LogDriver for JsonFile {
+  fn log(&mut self, entry: &LogEntry) -> Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    if self.config.max_size > 0
+      && self.size > 0
+      && self.size + line.len() as u64 > self.config.max_size
+    {
+      self.rotate()?;
+    }
+    // A single write, so that readers never see a part of a line.
+    self.file.write_all(&line)?;
+    self.size += line.len() as u64;
+    Ok(())
+  }
+}
+
+fn parse_line(line: &str) -> Option<LogEntry> {
+  match serde_json::from_str(line) {
+    Ok(entry) => Some(entry),
+    Err(err) => {
+      warn!("Skipping an invalid log entry: {}", err);
+      None
+    }
+  }
+}
+
+/// Read every entry that is logged for the container, the oldest first. The
+/// returned follower gets the entries logged afterwards.
+pub fn read(id: &str, config: &LogConfig) -> Result<(Vec<LogEntry>, Follower)> {
+  // Open the current file first, so that nothing logged in the meantime is
+  // missed.
+  let path = log_path(id);
+  let file = File::open(&path)?;
+  let mut follower = Follower {
+    path,
+    inode: file.metadata()?.ino(),
+    reader: BufReader::new(file),
+  };
+
+  let mut entries = vec![];
+  for index in (1..config.max_files).rev() {
+    let file = match File::open(rotated_path(id, index)) {
+      Ok(file) => file,
+      Err(_) => continue,
+    };
+    for line in BufReader::new(file).lines() {
+      entries.extend(parse_line(&line?));
+    }
+  }
+  entries.extend(follower.read()?);
+  Ok((entries, follower))
+}
+
+/// Reads the entries added to the current log file since the last call,
+/// across its rotations.
+pub struct Follower {
+  path: PathBuf,
+  reader: BufReader<File>,
+  inode: u64,
+}
+
+impl Follower {
+  fn read_file(&mut self, entries: &mut Vec<LogEntry>) -> Result<()> {
+    let mut line = String::new();
+    loop {
+      line.clear();
+      let len = self.reader.read_line(&mut line)?;
+      if len == 0 {
+        return Ok(());
+      }
+      if !line.ends_with('\n') {
+        // The rest of the line isn't written yet, read it again later.
+        self.reader.seek(SeekFrom::Current(-(len as i64)))?;
+        return Ok(());
+      }
+      entries.extend(parse_line(&line));
+    }
+  }
+
+  /// Get the new entries. Once the file is rotated, the rest of the old one
+  /// is read before switching to the new one. With a single log file, the
+  /// file is truncated instead and read again from its start.
+  pub fn read(&mut self) -> Result<Vec<LogEntry>> {
+    let mut entries = vec![];
+    self.read_file(&mut entries)?;
+    if let Ok(metadata) = fs::metadata(&self.path) {
+      if metadata.ino() != self.inode {
+        self.reader = BufReader::new(File::open(&self.path)?);
+        self.inode = metadata.ino();
+        self.read_file(&mut entries)?;
+      } else if metadata.len() < self.reader.stream_position()? {
+        self.reader.seek(SeekFrom::Start(0))?;
+        self.read_file(&mut entries)?;
+      }
+    }
+    Ok(entries)
+  }
+}
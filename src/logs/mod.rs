@@ -0,0 +1,216 @@
+mod command;
+mod json_file;
+pub mod time;
+
+pub use self::command::logs;
+
+use crate::cgroups;
+use crate::state::ContainerState;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::time::SystemTime;
+
+/// Longest line kept in a single entry, longer ones are split.
+const MAX_LINE_SIZE: usize = 16 * 1024;
+
+#[derive(Debug)]
+pub enum LogError {
+  InvalidDriver(String),
+  InvalidOption(String),
+  InvalidTime(String),
+  NotReadable(String),
+}
+
+impl fmt::Display for LogError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      LogError::InvalidDriver(driver) => write!(
+        f,
+        "Invalid log driver {}, expected json-file or none",
+        driver
+      ),
+      LogError::InvalidOption(option) => write!(f, "Invalid log option: {}", option),
+      LogError::InvalidTime(time) => write!(
+        f,
+        "Invalid time {}, expected a timestamp or a duration like 10m",
+        time
+      ),
+      LogError::NotReadable(driver) => {
+        write!(f, "The logs of the {} driver can't be read back", driver)
+      }
+    }
+  }
+}
+
+impl Error for LogError {}
+
+/// The stream of the container an entry comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+  Stdout,
+  Stderr,
+}
+
+/// A line of output of the container.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+  /// The line, with its line break.
+  pub log: String,
+  pub stream: Stream,
+  /// The time we read the line at, in RFC 3339.
+  pub time: String,
+}
+
+/// Where the output of containers goes. A driver gets every line of the
+/// container, one at a time.
+pub trait LogDriver: Send {
+  fn log(&mut self, entry: &LogEntry) -> Result<()>;
+}
+
+/// Drops the output, for the `none` driver.
+struct NoneDriver;
+
+impl LogDriver for NoneDriver {
+  fn log(&mut self, _entry: &LogEntry) -> Result<()> {
+    Ok(())
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DriverKind {
+  JsonFile,
+  None,
+}
+
+impl fmt::Display for DriverKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DriverKind::JsonFile => write!(f, "json-file"),
+      DriverKind::None => write!(f, "none"),
+    }
+  }
+}
+
+/// How the output of a container is logged.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogConfig {
+  pub driver: DriverKind,
+  /// Size in bytes a log file is rotated at, 0 to never rotate it.
+  pub max_size: u64,
+  /// Number of log files kept, including the current one.
+  pub max_files: u32,
+}
+
+impl Default for LogConfig {
+  fn default() -> Self {
+    LogConfig {
+      driver: DriverKind::JsonFile,
+      max_size: 10 * 1024 * 1024,
+      max_files: 3,
+    }
+  }
+}
+
+/// Get the logging options of the `run` subcommand, `--log-driver` and the
+/// `max-size` and `max-file` values of `--log-opt`.
+pub fn from_args(args: &clap::ArgMatches) -> Result<LogConfig> {
+  let driver = match args.value_of("log-driver") {
+    None | Some("json-file") => DriverKind::JsonFile,
+    Some("none") => DriverKind::None,
+    Some(driver) => return Err(Box::new(LogError::InvalidDriver(driver.to_string()))),
+  };
+  let mut config = LogConfig {
+    driver,
+    ..Default::default()
+  };
+
+  if let Some(values) = args.values_of("log-opt") {
+    for value in values {
+      let invalid = || Box::new(LogError::InvalidOption(value.to_string()));
+      let mut parts = value.splitn(2, '=');
+      match (parts.next(), parts.next()) {
+        (Some("max-size"), Some(size)) => match cgroups::parse_bytes(size) {
+          Ok(size) if size >= 0 => config.max_size = size as u64,
+          _ => return Err(invalid()),
+        },
+        (Some("max-file"), Some(count)) => match count.parse() {
+          Ok(count) if count > 0 => config.max_files = count,
+          _ => return Err(invalid()),
+        },
+        _ => return Err(invalid()),
+      }
+    }
+  }
+  Ok(config)
+}
+
+/// Splits the output of the container into lines for its log driver. The
+/// output of a stream can come in pieces, the last incomplete line of each
+/// stream waits for the rest.
+pub struct Logger {
+  driver: Box<dyn LogDriver>,
+  stdout: Vec<u8>,
+  stderr: Vec<u8>,
+}
+
+impl Logger {
+  /// Open the log driver of the container.
+  pub fn open(state: &ContainerState) -> Result<Self> {
+    let driver: Box<dyn LogDriver> = match state.log.driver {
+      DriverKind::JsonFile => Box::new(json_file::JsonFile::open(&state.id, &state.log)?),
+      DriverKind::None => Box::new(NoneDriver),
+    };
+    Ok(Logger {
+      driver,
+      stdout: vec![],
+      stderr: vec![],
+    })
+  }
+
+  fn buffer(&mut self, stream: Stream) -> &mut Vec<u8> {
+    match stream {
+      Stream::Stdout => &mut self.stdout,
+      Stream::Stderr => &mut self.stderr,
+    }
+  }
+
+  fn log_line(&mut self, stream: Stream, line: &[u8]) {
+    let entry = LogEntry {
+      log: String::from_utf8_lossy(line).into_owned(),
+      stream,
+      time: time::format(SystemTime::now()),
+    };
+    if let Err(err) = self.driver.log(&entry) {
+      warn!("Failed to log the output of the container: {}", err);
+    }
+  }
+
+  /// Log every complete line of the output.
+  pub fn write(&mut self, stream: Stream, data: &[u8]) {
+    let mut buffer = std::mem::take(self.buffer(stream));
+    buffer.extend_from_slice(data);
+    let mut start = 0;
+    while let Some(end) = buffer[start..].iter().position(|&byte| byte == b'\n') {
+      self.log_line(stream, &buffer[start..start + end + 1]);
+      start += end + 1;
+    }
+    while buffer.len() - start >= MAX_LINE_SIZE {
+      self.log_line(stream, &buffer[start..start + MAX_LINE_SIZE]);
+      start += MAX_LINE_SIZE;
+    }
+    buffer.drain(..start);
+    *self.buffer(stream) = buffer;
+  }
+
+  /// Log the incomplete line of the stream, once it's closed.
+  pub fn flush(&mut self, stream: Stream) {
+    let buffer = std::mem::take(self.buffer(stream));
+    if !buffer.is_empty() {
+      self.log_line(stream, &buffer);
+    }
+  }
+}
@@ -0,0 +1,140 @@
+use super::LogError;
+use crate::Result;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Days since the epoch of a date of the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: u64, day: u64) -> i64 {
+  let year = if month <= 2 { year - 1 } else { year };
+  let era = year.div_euclid(400);
+  let year_of_era = year - era * 400;
+  let month = if month > 2 { month - 3 } else { month + 9 } as i64;
+  let day_of_year = (153 * month + 2) / 5 + day as i64 - 1;
+  let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+  era * 146_097 + day_of_era - 719_468
+}
+
+/// The date of the given number of days since the epoch.
+fn civil_from_days(days: i64) -> (i64, u64, u64) {
+  let days = days + 719_468;
+  let era = days.div_euclid(146_097);
+  let day_of_era = days - era * 146_097;
+  let year_of_era =
+    (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let month = (5 * day_of_year + 2) / 153;
+  let day = (day_of_year - (153 * month + 2) / 5 + 1) as u64;
+  let month = if month < 10 { month + 3 } else { month - 9 } as u64;
+  let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+  (year, month, day)
+}
+
+/// Format a time as RFC 3339 in UTC with nanoseconds, like
+/// `2020-04-18T09:25:01.123456789Z`.
+pub fn format(time: SystemTime) -> String {
+  let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+  let seconds = since_epoch.as_secs();
+  let (year, month, day) = civil_from_days((seconds / SECONDS_PER_DAY) as i64);
+  let seconds_of_day = seconds % SECONDS_PER_DAY;
+  format!(
+    "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z",
+    year,
+    month,
+    day,
+    seconds_of_day / 3600,
+    seconds_of_day / 60 % 60,
+    seconds_of_day % 60,
+    since_epoch.subsec_nanos()
+  )
+}
+
+/// Parse an RFC 3339 time in UTC, as written by `format`.
+pub fn parse_rfc3339(value: &str) -> Option<SystemTime> {
+  if !value.ends_with('Z') {
+    return None;
+  }
+  let value = &value[..value.len() - 1];
+  let (date, time) = (value.get(..10)?, value.get(11..)?);
+  if value.as_bytes().get(10) != Some(&b'T') {
+    return None;
+  }
+
+  let date: Vec<&str> = date.split('-').collect();
+  let (year, month, day) = match date.as_slice() {
+    [year, month, day] => (year.parse().ok()?, month.parse().ok()?, day.parse().ok()?),
+    _ => return None,
+  };
+  let mut time = time.splitn(2, '.');
+  let clock: Vec<u64> = time
+    .next()?
+    .split(':')
+    .map(|field| field.parse().ok())
+    .collect::<Option<_>>()?;
+  let (hours, minutes, seconds) = match clock.as_slice() {
+    [hours, minutes, seconds] => (*hours, *minutes, *seconds),
+    _ => return None,
+  };
+  let nanos = match time.next() {
+    Some(fraction) if !fraction.is_empty() && fraction.len() <= 9 => {
+      fraction.parse::<u32>().ok()? * 10u32.pow(9 - fraction.len() as u32)
+    }
+    Some(_) => return None,
+    None => 0,
+  };
+  if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hours > 23 || minutes > 59 {
+    return None;
+  }
+
+  let days = days_from_civil(year, month, day);
+  if days < 0 {
+    return None;
+  }
+  let seconds = days as u64 * SECONDS_PER_DAY + hours * 3600 + minutes * 60 + seconds;
+  Some(UNIX_EPOCH + Duration::new(seconds, nanos))
+}
+
+/// Parse the value of `--since`: an RFC 3339 time, a Unix timestamp or a
+/// duration before now like `10m` or `1h30m`.
+pub fn parse_since(value: &str) -> Result<SystemTime> {
+  if let Some(time) = parse_rfc3339(value) {
+    return Ok(time);
+  }
+  if let Ok(timestamp) = value.parse::<f64>() {
+    if timestamp >= 0.0 {
+      return Ok(UNIX_EPOCH + Duration::from_secs_f64(timestamp));
+    }
+  }
+
+  let ago = parse_duration(value).ok_or_else(|| LogError::InvalidTime(value.to_string()))?;
+  Ok(SystemTime::now() - ago)
+}
+
+/// Parse a duration like `500ms`, `10m` or `1h30m`.
+pub fn parse_duration(value: &str) -> Option<Duration> {
+  let mut duration = Duration::default();
+  let mut rest = value;
+  while !rest.is_empty() {
+    let digits = rest
+      .find(|c: char| !c.is_ascii_digit())
+      .unwrap_or(rest.len());
+    let count: u64 = rest[..digits].parse().ok()?;
+    rest = &rest[digits..];
+    let letters = rest
+      .find(|c: char| c.is_ascii_digit())
+      .unwrap_or(rest.len());
+    duration += match &rest[..letters] {
+      "ms" => Duration::from_millis(count),
+      "s" => Duration::from_secs(count),
+      "m" => Duration::from_secs(count * 60),
+      "h" => Duration::from_secs(count * 60 * 60),
+      "d" => Duration::from_secs(count * SECONDS_PER_DAY),
+      _ => return None,
+    };
+    rest = &rest[letters..];
+  }
+  if duration == Duration::default() {
+    return None;
+  }
+  Some(duration)
+}
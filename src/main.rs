@@ -3,24 +3,55 @@ extern crate log;
 #[macro_use]
 extern crate clap;
 
+mod attach;
 mod cgroups;
+mod cleanup;
+mod console;
 mod container;
+mod devices;
+mod etc;
 mod fs;
+mod health;
 mod images;
+mod init;
+mod inspect;
+mod logs;
+mod network;
+mod pause;
 mod pull;
 mod registries;
+mod remove;
+mod restart;
+mod rlimits;
+mod state;
+mod stats;
+mod stop;
+mod supervisor;
+mod tty;
+mod update;
 
+use crate::attach::{attach, DetachKeys, Disconnect, DEFAULT_DETACH_KEYS};
+use crate::cleanup::cleanup;
 use crate::images::images;
+use crate::inspect::inspect;
+use crate::logs::logs;
+use crate::network::network;
+use crate::pause::{pause, unpause};
 use crate::pull::pull;
+use crate::remove::remove;
+use crate::stats::stats;
+use crate::stop::{kill, stop};
+use crate::update::update;
 use clap::{App, Arg, SubCommand};
-use container::Container;
 use std::env;
+use std::process;
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 /// It requires root privileges. Run the container ideally with `run.sh`.
-#[tokio::main]
-async fn main() -> Result<()> {
+/// The async runtime is only started for the subcommands that need it, the
+/// shim of a container is forked from us and can't inherit its threads.
+fn main() -> Result<()> {
     pretty_env_logger::init_timed();
 
     // Parse command line arguments.
@@ -31,11 +62,151 @@ async fn main() -> Result<()> {
         .subcommand(
             SubCommand::with_name("run")
                 .about("Run a container")
+                .args(&resource_args())
                 .args(&[
-                    // TODO: Add cgroup arguments here
-                    Arg::with_name("pids.max")
-                        .help("Limit the container processes (set -1 for unlimited)")
-                        .long("pids.max")
+                    Arg::with_name("device")
+                        .help("Add a host device to the container (e.g. /dev/fuse:rwm)")
+                        .long("device")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(false),
+                    Arg::with_name("device-cgroup-rule")
+                        .help("Allow access to devices (e.g. 'c 10:229 rwm')")
+                        .long("device-cgroup-rule")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(false),
+                    Arg::with_name("network")
+                        .help("Network to connect to (e.g. bridge), or none, host or container:<id>")
+                        .long("network")
+                        .takes_value(true)
+                        .required(false),
+                    Arg::with_name("publish")
+                        .help("Publish a port on the host ([hostip:]hostport:port[/udp])")
+                        .long("publish")
+                        .short("p")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(false),
+                    Arg::with_name("ip")
+                        .help("IPv4 address of the container on its network")
+                        .long("ip")
+                        .takes_value(true)
+                        .required(false),
+                    Arg::with_name("tty")
+                        .help("Run the command on a pseudo-terminal")
+                        .long("tty")
+                        .short("t")
+                        .required(false),
+                    Arg::with_name("interactive")
+                        .help("Keep the input of the command open")
+                        .long("interactive")
+                        .short("i")
+                        .required(false),
+                    Arg::with_name("detach")
+                        .help("Run the container in the background and print its ID")
+                        .long("detach")
+                        .short("d")
+                        .required(false),
+                    Arg::with_name("init")
+                        .help("Run an init inside the container that reaps processes")
+                        .long("init")
+                        .required(false),
+                    Arg::with_name("rm")
+                        .help("Remove the container once it exits")
+                        .long("rm")
+                        .required(false),
+                    Arg::with_name("health-cmd")
+                        .help("Command that checks the health of the container")
+                        .long("health-cmd")
+                        .takes_value(true)
+                        .required(false),
+                    Arg::with_name("health-interval")
+                        .help("Time between the health checks (30s by default)")
+                        .long("health-interval")
+                        .takes_value(true)
+                        .required(false),
+                    Arg::with_name("health-timeout")
+                        .help("Time a health check gets to finish (30s by default)")
+                        .long("health-timeout")
+                        .takes_value(true)
+                        .required(false),
+                    Arg::with_name("health-retries")
+                        .help("Failures in a row that make the container unhealthy (3 by default)")
+                        .long("health-retries")
+                        .takes_value(true)
+                        .required(false),
+                    Arg::with_name("health-start-period")
+                        .help("Time the container gets to start before failures count")
+                        .long("health-start-period")
+                        .takes_value(true)
+                        .required(false),
+                    Arg::with_name("restart")
+                        .help("Restart policy (no, on-failure[:max], always, unless-stopped)")
+                        .long("restart")
+                        .takes_value(true)
+                        .required(false),
+                    Arg::with_name("stop-signal")
+                        .help("Signal that stops the container (SIGTERM by default)")
+                        .long("stop-signal")
+                        .takes_value(true)
+                        .required(false),
+                    Arg::with_name("detach-keys")
+                        .help("Keys that detach from the container (ctrl-p,ctrl-q)")
+                        .long("detach-keys")
+                        .takes_value(true)
+                        .required(false),
+                    Arg::with_name("log-driver")
+                        .help("Log driver of the container (json-file or none)")
+                        .long("log-driver")
+                        .takes_value(true)
+                        .required(false),
+                    Arg::with_name("log-opt")
+                        .help("Log driver option (max-size=10m, max-file=3)")
+                        .long("log-opt")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(false),
+                    Arg::with_name("hostname")
+                        .help("Hostname of the container, its short ID by default")
+                        .long("hostname")
+                        .takes_value(true)
+                        .required(false),
+                    Arg::with_name("add-host")
+                        .help("Add an entry to /etc/hosts (e.g. db:10.5.0.3)")
+                        .long("add-host")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(false),
+                    Arg::with_name("dns")
+                        .help("Name server of the container")
+                        .long("dns")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(false),
+                    Arg::with_name("dns-search")
+                        .help("DNS search domain of the container")
+                        .long("dns-search")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(false),
+                    Arg::with_name("ulimit")
+                        .help("Set a resource limit of the container process (e.g. nofile=1024:2048)")
+                        .long("ulimit")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(false),
+                    Arg::with_name("oci-config")
+                        .help("Read the process.rlimits from an OCI runtime config.json")
+                        .long("oci-config")
                         .takes_value(true)
                         .required(false),
                     Arg::with_name("registry")
@@ -89,55 +260,416 @@ async fn main() -> Result<()> {
                     .takes_value(true)
                     .required(false)]),
         )
+        .subcommand(
+            SubCommand::with_name("update")
+                .about("Update the resource limits of a running container")
+                .args(&resource_args())
+                .arg(
+                    Arg::with_name("id")
+                        .help("ID of the container to update")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Show the resource usage of running containers")
+                .args(&[
+                    Arg::with_name("no-stream")
+                        .help("Print the usage once instead of refreshing it")
+                        .long("no-stream")
+                        .required(false),
+                    Arg::with_name("json")
+                        .help("Print the raw statistics once as JSON")
+                        .long("json")
+                        .required(false),
+                    Arg::with_name("id")
+                        .help("IDs of the containers, all running containers if not given")
+                        .multiple(true)
+                        .required(false),
+                ]),
+        )
+        .subcommand(
+            SubCommand::with_name("pause")
+                .about("Freeze every process of a running container")
+                .arg(
+                    Arg::with_name("id")
+                        .help("ID of the container to pause")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("unpause")
+                .about("Resume a paused container")
+                .arg(
+                    Arg::with_name("id")
+                        .help("ID of the container to unpause")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("attach")
+                .about("Attach to the console of a running container")
+                .args(&[
+                    Arg::with_name("detach-keys")
+                        .help("Keys that detach from the container (ctrl-p,ctrl-q)")
+                        .long("detach-keys")
+                        .takes_value(true)
+                        .required(false),
+                    Arg::with_name("id")
+                        .help("ID of the container")
+                        .required(true)
+                        .takes_value(true),
+                ]),
+        )
+        .subcommand(
+            SubCommand::with_name("logs")
+                .about("Show the output of a container")
+                .args(&[
+                    Arg::with_name("follow")
+                        .help("Keep printing the output until the container exits")
+                        .long("follow")
+                        .short("f")
+                        .required(false),
+                    Arg::with_name("since")
+                        .help("Only show the output since a timestamp or a duration (e.g. 10m)")
+                        .long("since")
+                        .takes_value(true)
+                        .required(false),
+                    Arg::with_name("tail")
+                        .help("Number of lines to show from the end, or all")
+                        .long("tail")
+                        .takes_value(true)
+                        .required(false),
+                    Arg::with_name("timestamps")
+                        .help("Show the time of every line")
+                        .long("timestamps")
+                        .required(false),
+                    Arg::with_name("id")
+                        .help("ID of the container")
+                        .required(true)
+                        .takes_value(true),
+                ]),
+        )
+        .subcommand(
+            SubCommand::with_name("stop")
+                .about("Stop a running container gracefully")
+                .args(&[
+                    Arg::with_name("time")
+                        .help("Seconds to wait before killing the container (10 by default)")
+                        .long("time")
+                        .short("t")
+                        .takes_value(true)
+                        .required(false),
+                    Arg::with_name("id")
+                        .help("ID of the container")
+                        .required(true)
+                        .takes_value(true),
+                ]),
+        )
+        .subcommand(
+            SubCommand::with_name("kill")
+                .about("Send a signal to a running container")
+                .args(&[
+                    Arg::with_name("signal")
+                        .help("Signal to send (SIGKILL by default)")
+                        .long("signal")
+                        .short("s")
+                        .takes_value(true)
+                        .required(false),
+                    Arg::with_name("id")
+                        .help("ID of the container")
+                        .required(true)
+                        .takes_value(true),
+                ]),
+        )
+        .subcommand(
+            SubCommand::with_name("inspect")
+                .about("Show the configuration and the state of a container or an image as JSON")
+                .arg(
+                    Arg::with_name("name")
+                        .help("ID of the container or name of the image")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rm")
+                .about("Remove containers that are not running")
+                .arg(
+                    Arg::with_name("id")
+                        .help("IDs of the containers")
+                        .required(true)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("network")
+                .about("Manage the networks of the containers")
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("Create a bridge network")
+                        .args(&[
+                            Arg::with_name("subnet")
+                                .help("Subnet of the network in CIDR notation")
+                                .long("subnet")
+                                .takes_value(true)
+                                .required(false),
+                            Arg::with_name("gateway")
+                                .help("Address of the bridge on the subnet")
+                                .long("gateway")
+                                .takes_value(true)
+                                .required(false),
+                            Arg::with_name("name")
+                                .help("Name of the network")
+                                .required(true)
+                                .takes_value(true),
+                        ]),
+                )
+                .subcommand(SubCommand::with_name("ls").about("List the networks"))
+                .subcommand(
+                    SubCommand::with_name("rm")
+                        .about("Remove networks without running containers")
+                        .arg(
+                            Arg::with_name("name")
+                                .help("Names of the networks")
+                                .required(true)
+                                .multiple(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("inspect")
+                        .about("Show the configuration and the containers of networks")
+                        .arg(
+                            Arg::with_name("name")
+                                .help("Names of the networks")
+                                .required(true)
+                                .multiple(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cleanup")
+                .about("Remove the cgroups left behind by containers that are gone"),
+        )
         .get_matches();
 
     info!("args: {:?}", matches);
 
+    // The exit code of the container for the `run` subcommand.
+    let mut code = 0;
+
     match matches.subcommand_name() {
-        Some("run") => run(matches
-            .subcommand_matches("run")
-            .expect("Failed to get subcommand matches")),
+        Some("run") => {
+            code = run(matches
+                .subcommand_matches("run")
+                .expect("Failed to get subcommand matches"))?
+        }
         Some("pull") => {
-            pull(
+            let mut runtime = tokio::runtime::Runtime::new()?;
+            runtime.block_on(pull(
                 matches
                     .subcommand_matches("pull")
                     .expect("Failed to get subcommand matches"),
-            )
-            .await?
+            ))?
         }
         Some("images") => images(
             matches
                 .subcommand_matches("images")
                 .expect("Failed to get subcommand matches"),
         )?,
+        Some("update") => update(
+            matches
+                .subcommand_matches("update")
+                .expect("Failed to get subcommand matches"),
+        )?,
+        Some("stats") => stats(
+            matches
+                .subcommand_matches("stats")
+                .expect("Failed to get subcommand matches"),
+        )?,
+        Some("pause") => pause(
+            matches
+                .subcommand_matches("pause")
+                .expect("Failed to get subcommand matches"),
+        )?,
+        Some("unpause") => unpause(
+            matches
+                .subcommand_matches("unpause")
+                .expect("Failed to get subcommand matches"),
+        )?,
+        Some("attach") => attach(
+            matches
+                .subcommand_matches("attach")
+                .expect("Failed to get subcommand matches"),
+        )?,
+        Some("logs") => logs(
+            matches
+                .subcommand_matches("logs")
+                .expect("Failed to get subcommand matches"),
+        )?,
+        Some("stop") => stop(
+            matches
+                .subcommand_matches("stop")
+                .expect("Failed to get subcommand matches"),
+        )?,
+        Some("kill") => kill(
+            matches
+                .subcommand_matches("kill")
+                .expect("Failed to get subcommand matches"),
+        )?,
+        Some("inspect") => inspect(
+            matches
+                .subcommand_matches("inspect")
+                .expect("Failed to get subcommand matches"),
+        )?,
+        Some("rm") => remove(
+            matches
+                .subcommand_matches("rm")
+                .expect("Failed to get subcommand matches"),
+        )?,
+        Some("network") => network(
+            matches
+                .subcommand_matches("network")
+                .expect("Failed to get subcommand matches"),
+        )?,
+        Some("cleanup") => cleanup(
+            matches
+                .subcommand_matches("cleanup")
+                .expect("Failed to get subcommand matches"),
+        )?,
         None => panic!("Expected a command but not found"),
         _ => unimplemented!(),
     }
 
     info!("exited!");
+    if code != 0 {
+        process::exit(code);
+    }
     Ok(())
 }
 
-/// Run the main process with the given argument.
-/// That function creates the child container process.
-fn run(args: &clap::ArgMatches<'static>) {
-    // Run the container process. This should initialize the process inside
-    // and return the container process to us.
-    let container = Container::new(args);
-    // Wait for the container process.
-    // TODO: support the detached state.
-    let status = container.wait();
-    info!(
-        "child process pid: {} status: {:?}",
-        i32::from(container.pid),
-        status
-    );
-
-    cleanup();
+/// Arguments of the cgroup resource limits. They are shared by the `run` and
+/// `update` subcommands.
+fn resource_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("pids.max")
+            .help("Limit the container processes (set -1 for unlimited)")
+            .long("pids.max")
+            .alias("pids-limit")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("memory")
+            .help("Memory limit of the container (e.g. 512m, 2g)")
+            .long("memory")
+            .short("m")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("memory-swap")
+            .help("Memory plus swap limit of the container (-1 for unlimited swap)")
+            .long("memory-swap")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("memory-reservation")
+            .help("Memory soft limit of the container")
+            .long("memory-reservation")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("cpus")
+            .help("Number of CPUs the container can use (e.g. 1.5)")
+            .long("cpus")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("cpu-shares")
+            .help("Relative CPU weight of the container (2-262144)")
+            .long("cpu-shares")
+            .short("c")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("cpu-quota")
+            .help("CPU time in microseconds the container can use in a period")
+            .long("cpu-quota")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("cpu-period")
+            .help("Length of the CPU period in microseconds")
+            .long("cpu-period")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("cpuset-cpus")
+            .help("CPUs the container can run on (e.g. 0-3,6)")
+            .long("cpuset-cpus")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("cpuset-mems")
+            .help("Memory nodes the container can allocate from (e.g. 0,1)")
+            .long("cpuset-mems")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("blkio-weight")
+            .help("Relative block IO weight of the container (10-1000)")
+            .long("blkio-weight")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("oom-kill-disable")
+            .help("Disable the OOM killer for the container")
+            .long("oom-kill-disable")
+            .required(false),
+        Arg::with_name("device-read-bps")
+            .help("Limit the read rate of a device (e.g. /dev/sda:1mb)")
+            .long("device-read-bps")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .required(false),
+        Arg::with_name("device-write-bps")
+            .help("Limit the write rate of a device (e.g. /dev/sda:1mb)")
+            .long("device-write-bps")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .required(false),
+        Arg::with_name("device-read-iops")
+            .help("Limit the read operations per second of a device")
+            .long("device-read-iops")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .required(false),
+        Arg::with_name("device-write-iops")
+            .help("Limit the write operations per second of a device")
+            .long("device-write-iops")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .required(false),
+    ]
 }
 
-/// Cleanup function after the container process. This should handle only the
-/// cgroups for now.
-fn cleanup() {
-    cgroups::deinit();
+/// Run the main process with the given argument.
+/// That function starts the supervisor of the container, which creates the
+/// container process, and attaches to it. Returns the exit code of the
+/// container, 128 plus the signal if it got killed, or 0 once detached.
+fn run(args: &clap::ArgMatches<'static>) -> Result<i32> {
+    let detach_keys =
+        DetachKeys::parse(args.value_of("detach-keys").unwrap_or(DEFAULT_DETACH_KEYS))?;
+    let (id, console) = supervisor::start(args)?;
+    if args.is_present("detach") {
+        println!("{}", id);
+        return Ok(0);
+    }
+    match attach::relay(
+        console,
+        args.is_present("tty"),
+        args.is_present("interactive"),
+        detach_keys,
+    )? {
+        Disconnect::Detached => {
+            info!("Detached from the container {}", id);
+            Ok(0)
+        }
+        Disconnect::Exited(code) => Ok(code),
+    }
 }
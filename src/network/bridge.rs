@@ -0,0 +1,108 @@
+use super::{config, ipam, nftables, Netlink, NetworkConfig};
+use crate::Result;
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::Ipv4Addr;
+
+/// Name of the interface inside the container.
+const CONTAINER_INTERFACE: &str = "eth0";
+const IP_FORWARD: &str = "/proc/sys/net/ipv4/ip_forward";
+
+/// The connection of a container to a bridge network.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Endpoint {
+  pub network: String,
+  pub bridge: String,
+  /// The host end of the veth pair.
+  pub veth: String,
+  pub address: Ipv4Addr,
+  pub prefix_len: u8,
+  pub gateway: Ipv4Addr,
+}
+
+/// Create the bridge of the network if it doesn't exist yet, and let the
+/// traffic of the containers out.
+fn setup_bridge(netlink: &mut Netlink, network: &NetworkConfig) -> Result<()> {
+  if netlink.find_link(&network.bridge)?.is_none() {
+    info!("Creating the bridge {}", network.bridge);
+    netlink.add_bridge(&network.bridge)?;
+    netlink.add_address(&network.bridge, network.gateway, network.subnet.prefix_len)?;
+  }
+  netlink.set_up(&network.bridge)?;
+
+  fs::write(IP_FORWARD, "1")?;
+  update_masquerade()
+}
+
+/// Masquerade the traffic of every network.
+pub fn update_masquerade() -> Result<()> {
+  let networks: Vec<(String, String)> = config::list()?
+    .into_iter()
+    .map(|network| (network.subnet.to_string(), network.bridge))
+    .collect();
+  nftables::set_masquerade(&networks)
+}
+
+/// Connect the container with the given ID and process to the bridge of the
+/// network with a veth pair, and configure its end of the pair.
+pub fn connect(
+  network: &NetworkConfig,
+  id: &str,
+  pid: Pid,
+  requested: Option<Ipv4Addr>,
+) -> Result<Endpoint> {
+  let mut netlink = Netlink::new()?;
+  setup_bridge(&mut netlink, network)?;
+
+  let endpoint = Endpoint {
+    network: network.name.clone(),
+    bridge: network.bridge.clone(),
+    // Interface names are limited to 15 characters.
+    veth: format!("veth{}", &id[..8]),
+    address: ipam::allocate(network, id, pid, requested)?,
+    prefix_len: network.subnet.prefix_len,
+    gateway: network.gateway,
+  };
+  if let Err(err) = attach(&mut netlink, &endpoint, pid) {
+    let _ = ipam::release(&endpoint.network, endpoint.address);
+    return Err(err);
+  }
+  info!("Container address: {}", endpoint.address);
+  Ok(endpoint)
+}
+
+fn attach(netlink: &mut Netlink, endpoint: &Endpoint, pid: Pid) -> Result<()> {
+  netlink.add_veth(&endpoint.veth, CONTAINER_INTERFACE, pid)?;
+  netlink.set_master(&endpoint.veth, &endpoint.bridge)?;
+  netlink.set_up(&endpoint.veth)?;
+
+  let mut container = Netlink::in_namespace(pid)?;
+  container.add_address(CONTAINER_INTERFACE, endpoint.address, endpoint.prefix_len)?;
+  container.set_up(CONTAINER_INTERFACE)?;
+  container.add_default_route(CONTAINER_INTERFACE, endpoint.gateway)
+}
+
+/// Remove the veth pair of the container. The pair is gone already if the
+/// network namespace of the container is. The container keeps its address.
+pub fn disconnect(endpoint: &Endpoint) -> Result<()> {
+  let mut netlink = Netlink::new()?;
+  if netlink.find_link(&endpoint.veth)?.is_some() {
+    netlink.delete_link(&endpoint.veth)?;
+  }
+  Ok(())
+}
+
+/// Give the address of the container back to the network.
+pub fn release(endpoint: &Endpoint) -> Result<()> {
+  ipam::release(&endpoint.network, endpoint.address)
+}
+
+/// Delete the bridge of a network that is being removed.
+pub fn remove_bridge(network: &NetworkConfig) -> Result<()> {
+  let mut netlink = Netlink::new()?;
+  if netlink.find_link(&network.bridge)?.is_some() {
+    netlink.delete_link(&network.bridge)?;
+  }
+  Ok(())
+}
@@ -0,0 +1,89 @@
+use super::config::{self, DEFAULT_NETWORK};
+use super::{bridge, ipam, NetworkConfig, NetworkError, Subnet};
+use crate::Result;
+use std::io::{self, Write};
+
+/// Main entry for the network subcommand.
+pub fn network(args: &clap::ArgMatches<'static>) -> Result<()> {
+  match args.subcommand() {
+    ("create", Some(args)) => create(args),
+    ("ls", Some(args)) => list(args),
+    ("rm", Some(args)) => remove(args),
+    ("inspect", Some(args)) => inspect(args),
+    _ => {
+      eprintln!("{}", args.usage());
+      Ok(())
+    }
+  }
+}
+
+fn create(args: &clap::ArgMatches<'static>) -> Result<()> {
+  let name = args.value_of("name").unwrap();
+  let subnet = match args.value_of("subnet") {
+    Some(subnet) => Some(Subnet::parse(subnet)?),
+    None => None,
+  };
+  let gateway = match args.value_of("gateway") {
+    Some(gateway) => Some(gateway.parse()?),
+    None => None,
+  };
+
+  let network = NetworkConfig::create(name, subnet, gateway)?;
+  println!("{}", network.id);
+  Ok(())
+}
+
+fn list(_args: &clap::ArgMatches<'static>) -> Result<()> {
+  let mut stdout = io::stdout();
+  writeln!(
+    &mut stdout,
+    "{:<12}  {:<20}  {:<15}  {:<18}  {:<15}",
+    "NETWORK ID", "NAME", "BRIDGE", "SUBNET", "GATEWAY"
+  )?;
+  for network in config::list()? {
+    writeln!(
+      &mut stdout,
+      "{:<12}  {:<20}  {:<15}  {:<18}  {:<15}",
+      &network.id[..12],
+      network.name,
+      network.bridge,
+      network.subnet.to_string(),
+      network.gateway.to_string()
+    )?;
+  }
+  Ok(())
+}
+
+fn remove(args: &clap::ArgMatches<'static>) -> Result<()> {
+  for name in args.values_of("name").unwrap() {
+    if name == DEFAULT_NETWORK {
+      return Err(Box::new(NetworkError::DefaultNetwork));
+    }
+    let network = NetworkConfig::load(name)?;
+    if !ipam::leases(name)?.is_empty() {
+      return Err(Box::new(NetworkError::NetworkInUse(name.to_string())));
+    }
+
+    bridge::remove_bridge(&network)?;
+    network.remove()?;
+    if let Err(err) = bridge::update_masquerade() {
+      warn!("Failed to remove the NAT rules of {}: {}", name, err);
+    }
+    println!("{}", name);
+  }
+  Ok(())
+}
+
+fn inspect(args: &clap::ArgMatches<'static>) -> Result<()> {
+  let mut networks = vec![];
+  for name in args.values_of("name").unwrap() {
+    let network = NetworkConfig::load(name)?;
+    let containers = ipam::leases(name)?;
+    networks.push(serde_json::json!({
+      "network": network,
+      "containers": containers,
+    }));
+  }
+  println!("{}", serde_json::to_string_pretty(&networks)?);
+  Ok(())
+}
@@ -0,0 +1,181 @@
+use super::ipam::Subnet;
+use super::NetworkError;
+use crate::fs::{get_network_path, get_networks_path};
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use sha2::digest::Digest;
+use sha2::Sha256;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::time::SystemTime;
+
+const CONFIG_FILE: &str = "network.json";
+
+/// The network containers are connected to when no other is given.
+pub const DEFAULT_NETWORK: &str = "bridge";
+const DEFAULT_BRIDGE: &str = "crs0";
+const DEFAULT_SUBNET: Subnet = Subnet::new(Ipv4Addr::new(172, 29, 0, 0), 16);
+
+/// A bridge network, persisted under `/var/container_rs/networks/<name>`
+/// next to the address leases of its containers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkConfig {
+  pub name: String,
+  pub id: String,
+  /// Name of the bridge interface on the host.
+  pub bridge: String,
+  pub subnet: Subnet,
+  /// Address of the bridge, which the containers route through.
+  pub gateway: Ipv4Addr,
+  pub created: u64,
+}
+
+impl NetworkConfig {
+  /// Define a new network. The subnet defaults to the first private range
+  /// that doesn't overlap with the other networks, and the gateway to the
+  /// first address of the subnet.
+  pub fn create(name: &str, subnet: Option<Subnet>, gateway: Option<Ipv4Addr>) -> Result<Self> {
+    validate_name(name)?;
+    if get_network_path(name).exists() || name == DEFAULT_NETWORK {
+      return Err(Box::new(NetworkError::NetworkExists(name.to_string())));
+    }
+
+    let networks = list()?;
+    let subnet = match subnet {
+      Some(subnet) => {
+        if let Some(network) = networks
+          .iter()
+          .find(|network| network.subnet.overlaps(&subnet))
+        {
+          return Err(Box::new(NetworkError::SubnetOverlaps(
+            subnet,
+            network.name.clone(),
+          )));
+        }
+        subnet
+      }
+      None => free_subnet(&networks)?,
+    };
+    let gateway = match gateway {
+      Some(gateway) if !subnet.is_host(gateway) => {
+        return Err(Box::new(NetworkError::AddressOutOfRange(
+          gateway,
+          name.to_string(),
+        )));
+      }
+      Some(gateway) => gateway,
+      None => subnet.hosts().next().unwrap(),
+    };
+
+    let created = SystemTime::now()
+      .duration_since(SystemTime::UNIX_EPOCH)
+      .unwrap();
+    let mut hasher = Sha256::new();
+    hasher.input(name.as_bytes());
+    hasher.input(created.as_nanos().to_be_bytes());
+    let id = hex::encode(hasher.result());
+
+    let network = NetworkConfig {
+      name: name.to_string(),
+      // Interface names are limited to 15 characters.
+      bridge: format!("crs-{}", &id[..11]),
+      id,
+      subnet,
+      gateway,
+      created: created.as_secs(),
+    };
+    network.save()?;
+    Ok(network)
+  }
+
+  fn save(&self) -> Result<()> {
+    let path = get_network_path(&self.name);
+    fs::create_dir_all(&path)?;
+    let tmp_path = path.join(format!("{}.tmp", CONFIG_FILE));
+    fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+    fs::rename(tmp_path, path.join(CONFIG_FILE))?;
+    Ok(())
+  }
+
+  /// Load the network with the given name. The default network is defined
+  /// the first time it's needed.
+  pub fn load(name: &str) -> Result<Self> {
+    let path = get_network_path(name).join(CONFIG_FILE);
+    if !path.exists() {
+      if name == DEFAULT_NETWORK {
+        return default_network();
+      }
+      return Err(Box::new(NetworkError::NetworkNotFound(name.to_string())));
+    }
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+  }
+
+  /// Remove the definition of the network and its leases.
+  pub fn remove(&self) -> Result<()> {
+    let path = get_network_path(&self.name);
+    if path.exists() {
+      fs::remove_dir_all(path)?;
+    }
+    Ok(())
+  }
+}
+
+fn default_network() -> Result<NetworkConfig> {
+  let network = NetworkConfig {
+    name: DEFAULT_NETWORK.to_string(),
+    id: hex::encode(Sha256::digest(DEFAULT_NETWORK.as_bytes())),
+    bridge: DEFAULT_BRIDGE.to_string(),
+    subnet: DEFAULT_SUBNET,
+    gateway: DEFAULT_SUBNET.hosts().next().unwrap(),
+    created: SystemTime::now()
+      .duration_since(SystemTime::UNIX_EPOCH)
+      .unwrap()
+      .as_secs(),
+  };
+  network.save()?;
+  Ok(network)
+}
+
+/// The names are used as directory names, and must not be confused with the
+/// other network modes.
+fn validate_name(name: &str) -> Result<()> {
+  let valid_chars = name
+    .chars()
+    .all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c));
+  if name.is_empty() || !valid_chars || name.starts_with('.') || name == "none" || name == "host" {
+    return Err(Box::new(NetworkError::InvalidName(name.to_string())));
+  }
+  Ok(())
+}
+
+/// Pick a private subnet that no network uses yet.
+fn free_subnet(networks: &[NetworkConfig]) -> Result<Subnet> {
+  let candidates = (30..32)
+    .map(|octet| Subnet::new(Ipv4Addr::new(172, octet, 0, 0), 16))
+    .chain((0..=255).map(|octet| Subnet::new(Ipv4Addr::new(192, 168, octet, 0), 24)));
+  for subnet in candidates {
+    if !networks
+      .iter()
+      .any(|network| network.subnet.overlaps(&subnet))
+    {
+      return Ok(subnet);
+    }
+  }
+  Err(Box::new(NetworkError::NoSubnetAvailable))
+}
+
+/// Load every network, including the default one.
+pub fn list() -> Result<Vec<NetworkConfig>> {
+  let mut networks = vec![NetworkConfig::load(DEFAULT_NETWORK)?];
+  for entry in fs::read_dir(get_networks_path())? {
+    let name = entry?.file_name().into_string().unwrap();
+    if name == DEFAULT_NETWORK {
+      continue;
+    }
+    match NetworkConfig::load(&name) {
+      Ok(network) => networks.push(network),
+      Err(err) => warn!("Skipping the network {}: {}", name, err),
+    }
+  }
+  Ok(networks)
+}
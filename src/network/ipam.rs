@@ -0,0 +1,262 @@
+use super::{NetworkConfig, NetworkError};
+use crate::fs::{get_container_path, get_network_path};
+use crate::Result;
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+
+const LEASES_DIR: &str = "leases";
+
+/// An IPv4 subnet in CIDR notation, like `172.29.0.0/16`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct Subnet {
+  pub address: Ipv4Addr,
+  pub prefix_len: u8,
+}
+
+impl Subnet {
+  pub const fn new(address: Ipv4Addr, prefix_len: u8) -> Self {
+    Subnet {
+      address,
+      prefix_len,
+    }
+  }
+
+  /// Parse a subnet. The address has to be the first one of the subnet, and
+  /// the subnet needs room for a gateway and at least one container.
+  pub fn parse(value: &str) -> std::result::Result<Self, NetworkError> {
+    let invalid = || NetworkError::InvalidSubnet(value.to_string());
+    let mut parts = value.splitn(2, '/');
+    let address: Ipv4Addr = parts.next().unwrap().parse().map_err(|_| invalid())?;
+    let prefix_len: u8 = match parts.next() {
+      Some(prefix_len) => prefix_len.parse().map_err(|_| invalid())?,
+      None => return Err(invalid()),
+    };
+    if prefix_len > 30 {
+      return Err(invalid());
+    }
+
+    let subnet = Subnet::new(address, prefix_len);
+    if u32::from(address) & !subnet.mask() != 0 {
+      return Err(invalid());
+    }
+    Ok(subnet)
+  }
+
+  fn mask(&self) -> u32 {
+    match self.prefix_len {
+      0 => 0,
+      prefix_len => !0 << (32 - u32::from(prefix_len)),
+    }
+  }
+
+  pub fn contains(&self, address: Ipv4Addr) -> bool {
+    u32::from(address) & self.mask() == u32::from(self.address)
+  }
+
+  pub fn overlaps(&self, other: &Subnet) -> bool {
+    self.contains(other.address) || other.contains(self.address)
+  }
+
+  /// The addresses that can be assigned to interfaces, which leaves out the
+  /// network and the broadcast addresses.
+  pub fn hosts(&self) -> impl Iterator<Item = Ipv4Addr> {
+    let network = u32::from(self.address);
+    let broadcast = network | !self.mask();
+    (network + 1..broadcast).map(Ipv4Addr::from)
+  }
+
+  pub fn is_host(&self, address: Ipv4Addr) -> bool {
+    let broadcast = u32::from(self.address) | !self.mask();
+    self.contains(address) && address != self.address && u32::from(address) != broadcast
+  }
+}
+
+impl fmt::Display for Subnet {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}/{}", self.address, self.prefix_len)
+  }
+}
+
+impl From<Subnet> for String {
+  fn from(subnet: Subnet) -> Self {
+    subnet.to_string()
+  }
+}
+
+impl TryFrom<String> for Subnet {
+  type Error = NetworkError;
+
+  fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+    Subnet::parse(&value)
+  }
+}
+
+/// An address held by a container until it's removed. Every lease is a file
+/// named after the address, so that creating the file claims the address
+/// atomically.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lease {
+  pub address: Ipv4Addr,
+  pub container: String,
+  /// Init process of the container.
+  pub pid: i32,
+}
+
+impl Lease {
+  /// Whether the container is gone without releasing the lease. A starting
+  /// container has no state yet, but its init process runs.
+  fn is_stale(&self) -> bool {
+    kill(Pid::from_raw(self.pid), None).is_err() && !get_container_path(&self.container).exists()
+  }
+}
+
+fn leases_path(network: &str) -> PathBuf {
+  get_network_path(network).join(LEASES_DIR)
+}
+
+/// Try to claim the address for the container. A stale lease is taken over.
+fn claim(lease: &Lease, network: &str) -> Result<bool> {
+  let path = leases_path(network).join(lease.address.to_string());
+  for _ in 0..2 {
+    match OpenOptions::new().write(true).create_new(true).open(&path) {
+      Ok(mut file) => {
+        file.write_all(&serde_json::to_vec(lease)?)?;
+        return Ok(true);
+      }
+      Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {
+        // The holder may still be writing the file, in which case it doesn't
+        // parse yet and the lease is not stale.
+        let holder: Option<Lease> = serde_json::from_slice(&fs::read(&path)?).ok();
+        match holder {
+          // A restarted container gets its address back.
+          Some(holder) if holder.container == lease.container => {
+            fs::write(&path, serde_json::to_vec(lease)?)?;
+            return Ok(true);
+          }
+          Some(holder) if holder.is_stale() => {
+            info!(
+              "Releasing the stale lease of {} on {}",
+              holder.address, network
+            );
+            fs::remove_file(&path)?;
+          }
+          _ => return Ok(false),
+        }
+      }
+      Err(err) => return Err(Box::new(err)),
+    }
+  }
+  Ok(false)
+}
+
+/// Allocate an address of the network for the container with the given ID
+/// and init process. The requested address is used if there is one, the
+/// first free address otherwise.
+pub fn allocate(
+  network: &NetworkConfig,
+  container: &str,
+  pid: Pid,
+  requested: Option<Ipv4Addr>,
+) -> Result<Ipv4Addr> {
+  fs::create_dir_all(leases_path(&network.name))?;
+  let lease = |address| Lease {
+    address,
+    container: container.to_string(),
+    pid: i32::from(pid),
+  };
+
+  if let Some(address) = requested {
+    if !network.subnet.is_host(address) || address == network.gateway {
+      return Err(Box::new(NetworkError::AddressOutOfRange(
+        address,
+        network.name.clone(),
+      )));
+    }
+    if !claim(&lease(address), &network.name)? {
+      return Err(Box::new(NetworkError::AddressInUse(address)));
+    }
+    return Ok(address);
+  }
+
+  for address in network.subnet.hosts() {
+    if address != network.gateway && claim(&lease(address), &network.name)? {
+      return Ok(address);
+    }
+  }
+  Err(Box::new(NetworkError::NoAddressAvailable(
+    network.name.clone(),
+  )))
+}
+
+/// Give the address back to the network.
+pub fn release(network: &str, address: Ipv4Addr) -> Result<()> {
+  let path = leases_path(network).join(address.to_string());
+  if path.exists() {
+    fs::remove_file(path)?;
+  }
+  Ok(())
+}
+
+/// The leases of the network that are still held by containers.
+pub fn leases(network: &str) -> Result<Vec<Lease>> {
+  let path = leases_path(network);
+  if !path.exists() {
+    return Ok(vec![]);
+  }
+
+  let mut leases = vec![];
+  for entry in fs::read_dir(path)? {
+    if let Ok(lease) = serde_json::from_slice::<Lease>(&fs::read(entry?.path())?) {
+      if !lease.is_stale() {
+        leases.push(lease);
+      }
+    }
+  }
+  leases.sort_by_key(|lease| lease.address);
+  Ok(leases)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_subnets() {
+    let subnet = Subnet::parse("172.18.0.0/16").unwrap();
+    assert_eq!(subnet, Subnet::new(Ipv4Addr::new(172, 18, 0, 0), 16));
+    assert!(subnet.contains(Ipv4Addr::new(172, 18, 255, 255)));
+    assert!(!subnet.contains(Ipv4Addr::new(172, 19, 0, 0)));
+  }
+
+  #[test]
+  fn parse_prefix_bounds() {
+    assert!(Subnet::parse("0.0.0.0/0").is_ok());
+    let subnet = Subnet::parse("10.0.0.4/30").unwrap();
+    assert_eq!(subnet.hosts().count(), 2);
+    assert!(Subnet::parse("10.0.0.4/31").is_err());
+    assert!(Subnet::parse("10.0.0.4/32").is_err());
+    assert!(Subnet::parse("10.0.0.0/256").is_err());
+  }
+
+  #[test]
+  fn parse_invalid_subnets() {
+    for value in &[
+      "",
+      "10.0.0.0",
+      "10.0.0.0/",
+      "10.0.0.0/-1",
+      "10.0.0.1/24",
+      "10.0.0/24",
+    ] {
+      assert!(Subnet::parse(value).is_err(), "{}", value);
+    }
+  }
+}
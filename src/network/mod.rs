@@ -0,0 +1,244 @@
+mod bridge;
+mod command;
+mod config;
+mod ipam;
+mod netlink;
+mod nftables;
+mod ports;
+mod proxy;
+
+pub use self::bridge::Endpoint;
+pub use self::command::network;
+pub use self::config::NetworkConfig;
+pub use self::ipam::Subnet;
+pub use self::netlink::Netlink;
+pub use self::ports::PortMapping;
+
+use crate::state::{self, ContainerState};
+use crate::Result;
+use nix::fcntl::{open, OFlag};
+use nix::sched::CloneFlags;
+use nix::sys::stat::Mode;
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::Ipv4Addr;
+use std::os::unix::io::RawFd;
+
+const LOOPBACK: &str = "lo";
+
+#[derive(Debug)]
+pub enum NetworkError {
+  InvalidMode(String),
+  InvalidName(String),
+  InvalidSubnet(String),
+  NetworkExists(String),
+  NetworkNotFound(String),
+  NetworkInUse(String),
+  DefaultNetwork,
+  SubnetOverlaps(Subnet, String),
+  NoSubnetAvailable,
+  AddressOutOfRange(Ipv4Addr, String),
+  AddressInUse(Ipv4Addr),
+  AddressWithoutBridge,
+  InvalidPort(String),
+  PortInUse(String),
+  PortsWithoutBridge,
+  NoAddressAvailable(String),
+  LinkNotFound(String),
+  Netlink(String, io::Error),
+  Nftables(String),
+  NftablesNotFound,
+}
+
+impl fmt::Display for NetworkError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      NetworkError::InvalidMode(mode) => write!(
+        f,
+        "Invalid network mode {}, expected a network, none, host or container:<id>",
+        mode
+      ),
+      NetworkError::InvalidName(name) => write!(f, "Invalid network name: {}", name),
+      NetworkError::InvalidSubnet(subnet) => write!(f, "Invalid subnet: {}", subnet),
+      NetworkError::NetworkExists(name) => write!(f, "Network {} already exists", name),
+      NetworkError::NetworkNotFound(name) => write!(f, "No such network: {}", name),
+      NetworkError::NetworkInUse(name) => {
+        write!(
+          f,
+          "Network {} has containers connected, remove them first",
+          name
+        )
+      }
+      NetworkError::DefaultNetwork => write!(f, "The default network can't be removed"),
+      NetworkError::SubnetOverlaps(subnet, name) => {
+        write!(f, "Subnet {} overlaps with the network {}", subnet, name)
+      }
+      NetworkError::NoSubnetAvailable => write!(f, "No free subnet is left for a new network"),
+      NetworkError::AddressOutOfRange(address, name) => write!(
+        f,
+        "Address {} can't be assigned on the network {}",
+        address, name
+      ),
+      NetworkError::AddressInUse(address) => write!(f, "Address {} is already in use", address),
+      NetworkError::AddressWithoutBridge => {
+        write!(f, "An address can only be given for a bridge network")
+      }
+      NetworkError::InvalidPort(port) => write!(f, "Invalid port mapping: {}", port),
+      NetworkError::PortInUse(port) => write!(f, "Port {} is already published", port),
+      NetworkError::PortsWithoutBridge => {
+        write!(f, "Ports can only be published for a bridge network")
+      }
+      NetworkError::NoAddressAvailable(name) => {
+        write!(f, "No address is available on the {} network", name)
+      }
+      NetworkError::LinkNotFound(name) => write!(f, "Network interface {} not found", name),
+      NetworkError::Netlink(operation, err) => write!(f, "Failed to {}: {}", operation, err),
+      NetworkError::Nftables(err) => write!(f, "Failed to set up the firewall: {}", err),
+      NetworkError::NftablesNotFound => write!(
+        f,
+        "Bridge networks need the nft command, install nftables or use --network none or host"
+      ),
+    }
+  }
+}
+
+impl Error for NetworkError {}
+
+/// How the network of a container is set up.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkMode {
+  /// A network namespace of its own, connected to the bridge of the network
+  /// with the given name.
+  Bridge(String),
+  /// A network namespace of its own with only the loopback interface.
+  None,
+  /// The network namespace of the host.
+  Host,
+  /// The network namespace of the running container with the given ID.
+  Container(String),
+}
+
+/// Containers only get the loopback interface unless they ask for a network,
+/// bridge networks need nftables on the host.
+impl Default for NetworkMode {
+  fn default() -> Self {
+    NetworkMode::None
+  }
+}
+
+impl fmt::Display for NetworkMode {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      NetworkMode::Bridge(name) => write!(f, "{}", name),
+      NetworkMode::None => write!(f, "none"),
+      NetworkMode::Host => write!(f, "host"),
+      NetworkMode::Container(id) => write!(f, "container:{}", id),
+    }
+  }
+}
+
+impl NetworkMode {
+  /// Parse a `--network` value. The ID of a container can be a prefix, it's
+  /// resolved to the full ID of a running container. Any other value names a
+  /// network.
+  pub fn parse(value: &str) -> Result<Self> {
+    match value {
+      "none" => Ok(NetworkMode::None),
+      "host" => Ok(NetworkMode::Host),
+      _ if value.starts_with("container:") => {
+        let state = state::find_running(&value["container:".len()..])?;
+        Ok(NetworkMode::Container(state.id))
+      }
+      _ if value.contains(':') => Err(Box::new(NetworkError::InvalidMode(value.to_string()))),
+      _ => Ok(NetworkMode::Bridge(NetworkConfig::load(value)?.name)),
+    }
+  }
+
+  /// The namespace flags the container process is cloned with.
+  pub fn clone_flags(&self) -> CloneFlags {
+    match self {
+      NetworkMode::Bridge(_) | NetworkMode::None => CloneFlags::CLONE_NEWNET,
+      NetworkMode::Host | NetworkMode::Container(_) => CloneFlags::empty(),
+    }
+  }
+
+  /// Open the network namespace the container has to join, if it joins an
+  /// existing one. The container process inherits the descriptor.
+  pub fn open_namespace(&self) -> Result<Option<RawFd>> {
+    match self {
+      NetworkMode::Container(id) => {
+        let state = state::find_running(id)?;
+        let path = format!("/proc/{}/ns/net", state.pid);
+        let fd = open(path.as_str(), OFlag::O_RDONLY, Mode::empty())?;
+        Ok(Some(fd))
+      }
+      _ => Ok(None),
+    }
+  }
+}
+
+/// Get the network mode of the `run` subcommand, the address requested for
+/// the container and its published ports.
+pub fn from_args(
+  args: &clap::ArgMatches,
+) -> Result<(NetworkMode, Option<Ipv4Addr>, Vec<PortMapping>)> {
+  let mode = match args.value_of("network") {
+    Some(value) => NetworkMode::parse(value)?,
+    None => NetworkMode::default(),
+  };
+  let ip = match args.value_of("ip") {
+    Some(_) if !matches!(mode, NetworkMode::Bridge(_)) => {
+      return Err(Box::new(NetworkError::AddressWithoutBridge));
+    }
+    Some(ip) => Some(ip.parse()?),
+    None => None,
+  };
+  if let NetworkMode::Bridge(_) = mode {
+    nftables::check()?;
+  }
+  let ports = ports::from_args(args, &mode)?;
+  Ok((mode, ip, ports))
+}
+
+/// Configure the network namespace of a new container from the outside,
+/// before it starts executing its command.
+pub fn setup(state: &mut ContainerState, pid: Pid) -> Result<()> {
+  match &state.network {
+    NetworkMode::Bridge(name) => {
+      Netlink::in_namespace(pid)?.set_up(LOOPBACK)?;
+      let network = NetworkConfig::load(name)?;
+      let endpoint = bridge::connect(&network, &state.id, pid, state.ip)?;
+      let address = endpoint.address;
+      state.endpoint = Some(endpoint);
+      ports::publish(&state.ports, address)?;
+      proxy::start(&state.ports, address)?;
+    }
+    NetworkMode::None => Netlink::in_namespace(pid)?.set_up(LOOPBACK)?,
+    NetworkMode::Host | NetworkMode::Container(_) => {}
+  }
+  Ok(())
+}
+
+/// Release the network resources of a container that exited. Its address
+/// stays reserved until the container is removed.
+pub fn teardown(state: &ContainerState) -> Result<()> {
+  if let Some(endpoint) = &state.endpoint {
+    if let Err(err) = ports::unpublish(&state.ports) {
+      warn!("Failed to unpublish the ports of the container: {}", err);
+    }
+    bridge::disconnect(endpoint)?;
+  }
+  Ok(())
+}
+
+/// Release the address of a container that is being removed.
+pub fn release(state: &ContainerState) -> Result<()> {
+  if let Some(endpoint) = &state.endpoint {
+    bridge::release(endpoint)?;
+  }
+  Ok(())
+}
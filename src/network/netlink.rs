@@ -0,0 +1,373 @@
+use super::NetworkError;
+use crate::Result;
+use nix::fcntl::{open, OFlag};
+use nix::libc;
+use nix::sched::{setns, CloneFlags};
+use nix::sys::stat::Mode;
+use nix::unistd::{close, Pid};
+use std::io;
+use std::net::Ipv4Addr;
+use std::os::unix::io::RawFd;
+use std::thread;
+
+const HEADER_LEN: usize = 16;
+const RECV_BUFFER_SIZE: usize = 32 * 1024;
+
+const RTM_NEWLINK: u16 = libc::RTM_NEWLINK;
+const RTM_DELLINK: u16 = libc::RTM_DELLINK;
+const RTM_GETLINK: u16 = libc::RTM_GETLINK;
+const RTM_NEWADDR: u16 = libc::RTM_NEWADDR;
+const RTM_NEWROUTE: u16 = libc::RTM_NEWROUTE;
+const NLMSG_ERROR: u16 = libc::NLMSG_ERROR as u16;
+const NLM_F_REQUEST: u16 = libc::NLM_F_REQUEST as u16;
+const NLM_F_ACK: u16 = libc::NLM_F_ACK as u16;
+const NLM_F_CREATE: u16 = libc::NLM_F_CREATE as u16;
+const NLM_F_EXCL: u16 = libc::NLM_F_EXCL as u16;
+const IFLA_IFNAME: u16 = libc::IFLA_IFNAME;
+const IFLA_MASTER: u16 = libc::IFLA_MASTER;
+const IFLA_LINKINFO: u16 = libc::IFLA_LINKINFO;
+const IFLA_NET_NS_PID: u16 = libc::IFLA_NET_NS_PID;
+const IFLA_INFO_KIND: u16 = libc::IFLA_INFO_KIND;
+const IFLA_INFO_DATA: u16 = libc::IFLA_INFO_DATA;
+const VETH_INFO_PEER: u16 = 1;
+const IFA_LOCAL: u16 = libc::IFA_LOCAL;
+const IFA_ADDRESS: u16 = libc::IFA_ADDRESS;
+const RTA_GATEWAY: u16 = libc::RTA_GATEWAY;
+const RTA_OIF: u16 = libc::RTA_OIF;
+
+/// A netlink request. The header is filled in when the request is sent.
+struct Message {
+  buf: Vec<u8>,
+  kind: u16,
+  flags: u16,
+}
+
+impl Message {
+  fn new(kind: u16, flags: u16) -> Self {
+    Message {
+      buf: vec![0; HEADER_LEN],
+      kind,
+      flags: flags | NLM_F_REQUEST | NLM_F_ACK,
+    }
+  }
+
+  /// Append the fixed part of the message, or the data of an attribute.
+  fn push(&mut self, bytes: &[u8]) {
+    self.buf.extend_from_slice(bytes);
+    self.pad();
+  }
+
+  /// Everything in a message is aligned to 4 bytes.
+  fn pad(&mut self) {
+    let len = (self.buf.len() + 3) & !3;
+    self.buf.resize(len, 0);
+  }
+
+  fn attr(&mut self, kind: u16, data: &[u8]) {
+    self
+      .buf
+      .extend_from_slice(&((4 + data.len()) as u16).to_ne_bytes());
+    self.buf.extend_from_slice(&kind.to_ne_bytes());
+    self.push(data);
+  }
+
+  /// Start an attribute holding other attributes. It's closed with `end`,
+  /// which fills in its length.
+  fn begin(&mut self, kind: u16) -> usize {
+    let start = self.buf.len();
+    self.attr(kind, &[]);
+    start
+  }
+
+  fn end(&mut self, start: usize) {
+    let len = (self.buf.len() - start) as u16;
+    self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+  }
+
+  /// Add a link message header for the given interface index.
+  fn link(&mut self, index: i32, flags: u32, change: u32) {
+    let mut ifinfomsg = vec![libc::AF_UNSPEC as u8, 0, 0, 0];
+    ifinfomsg.extend_from_slice(&index.to_ne_bytes());
+    ifinfomsg.extend_from_slice(&flags.to_ne_bytes());
+    ifinfomsg.extend_from_slice(&change.to_ne_bytes());
+    self.push(&ifinfomsg);
+  }
+
+  /// Add an IPv4 address message header for the given interface index.
+  fn address(&mut self, index: i32, prefix_len: u8) {
+    let mut ifaddrmsg = vec![libc::AF_INET as u8, prefix_len, 0, libc::RT_SCOPE_UNIVERSE];
+    ifaddrmsg.extend_from_slice(&(index as u32).to_ne_bytes());
+    self.push(&ifaddrmsg);
+  }
+
+  /// Add an IPv4 route message header for a route of the main table.
+  fn route(&mut self, dst_len: u8) {
+    let rtmsg = [
+      libc::AF_INET as u8,
+      dst_len,
+      0,
+      0,
+      libc::RT_TABLE_MAIN,
+      libc::RTPROT_BOOT,
+      libc::RT_SCOPE_UNIVERSE,
+      libc::RTN_UNICAST,
+      0,
+      0,
+      0,
+      0,
+    ];
+    self.push(&rtmsg);
+  }
+
+  fn finish(mut self, seq: u32) -> Vec<u8> {
+    let len = self.buf.len() as u32;
+    self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+    self.buf[4..6].copy_from_slice(&self.kind.to_ne_bytes());
+    self.buf[6..8].copy_from_slice(&self.flags.to_ne_bytes());
+    self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+    self.buf
+  }
+}
+
+/// Null terminated interface name, the format of `IFLA_IFNAME`.
+fn ifname(name: &str) -> Vec<u8> {
+  let mut bytes = name.as_bytes().to_vec();
+  bytes.push(0);
+  bytes
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+  let mut bytes = [0; 2];
+  bytes.copy_from_slice(&buf[offset..offset + 2]);
+  u16::from_ne_bytes(bytes)
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+  let mut bytes = [0; 4];
+  bytes.copy_from_slice(&buf[offset..offset + 4]);
+  u32::from_ne_bytes(bytes)
+}
+
+fn socket() -> nix::Result<RawFd> {
+  let fd = unsafe {
+    libc::socket(
+      libc::AF_NETLINK,
+      libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+      libc::NETLINK_ROUTE,
+    )
+  };
+  if fd < 0 {
+    return Err(nix::Error::last());
+  }
+  Ok(fd)
+}
+
+fn io_result(ret: isize) -> io::Result<usize> {
+  if ret < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(ret as usize)
+}
+
+/// A route netlink socket, which configures the network devices, addresses
+/// and routes of the network namespace it was opened in.
+pub struct Netlink {
+  fd: RawFd,
+  seq: u32,
+}
+
+impl Netlink {
+  /// Open a socket in our own network namespace.
+  pub fn new() -> Result<Self> {
+    Ok(Netlink {
+      fd: socket()?,
+      seq: 0,
+    })
+  }
+
+  /// Open a socket in the network namespace of the given process. The socket
+  /// stays in that namespace, so only the thread that opens it has to enter
+  /// the namespace.
+  pub fn in_namespace(pid: Pid) -> Result<Self> {
+    let path = format!("/proc/{}/ns/net", pid);
+    let fd = thread::spawn(move || -> nix::Result<RawFd> {
+      let namespace = open(
+        path.as_str(),
+        OFlag::O_RDONLY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+      )?;
+      let result = setns(namespace, CloneFlags::CLONE_NEWNET).and_then(|_| socket());
+      let _ = close(namespace);
+      result
+    })
+    .join()
+    .expect("Failed to join the netlink thread")?;
+    Ok(Netlink { fd, seq: 0 })
+  }
+
+  /// Send a request and collect the replies until the kernel acknowledges it.
+  fn request(&mut self, message: Message, operation: &str) -> Result<Vec<Vec<u8>>> {
+    self
+      .transact(message)
+      .map_err(|err| Box::new(NetworkError::Netlink(operation.to_string(), err)).into())
+  }
+
+  fn transact(&mut self, message: Message) -> io::Result<Vec<Vec<u8>>> {
+    self.seq += 1;
+    let seq = self.seq;
+    let request = message.finish(seq);
+    io_result(unsafe { libc::send(self.fd, request.as_ptr() as *const _, request.len(), 0) })?;
+
+    let mut replies = vec![];
+    let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+    loop {
+      let len =
+        io_result(unsafe { libc::recv(self.fd, buf.as_mut_ptr() as *mut _, buf.len(), 0) })?;
+      let mut offset = 0;
+      while offset + HEADER_LEN <= len {
+        let message_len = read_u32(&buf, offset) as usize;
+        if message_len < HEADER_LEN || offset + message_len > len {
+          return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "truncated reply",
+          ));
+        }
+        let message = &buf[offset..offset + message_len];
+        offset += (message_len + 3) & !3;
+        if read_u32(message, 8) != seq {
+          continue;
+        }
+
+        if read_u16(message, 4) == NLMSG_ERROR {
+          // An acknowledgement is an error message with a zero error code.
+          let errno = read_u32(message, HEADER_LEN) as i32;
+          if errno == 0 {
+            return Ok(replies);
+          }
+          return Err(io::Error::from_raw_os_error(-errno));
+        }
+        replies.push(message[HEADER_LEN..].to_vec());
+      }
+    }
+  }
+
+  /// Get the index of the interface with the given name, `None` if there is
+  /// no such interface.
+  pub fn find_link(&mut self, name: &str) -> Result<Option<i32>> {
+    let mut message = Message::new(RTM_GETLINK, 0);
+    message.link(0, 0, 0);
+    message.attr(IFLA_IFNAME, &ifname(name));
+    match self.transact(message) {
+      // The index follows the family, padding and type of the ifinfomsg.
+      Ok(replies) => match replies.first() {
+        Some(reply) if reply.len() >= 8 => Ok(Some(read_u32(reply, 4) as i32)),
+        _ => Ok(None),
+      },
+      Err(ref err) if err.raw_os_error() == Some(libc::ENODEV) => Ok(None),
+      Err(err) => Err(Box::new(NetworkError::Netlink(
+        format!("find the interface {}", name),
+        err,
+      ))),
+    }
+  }
+
+  /// Get the index of the interface with the given name.
+  pub fn link_index(&mut self, name: &str) -> Result<i32> {
+    match self.find_link(name)? {
+      Some(index) => Ok(index),
+      None => Err(Box::new(NetworkError::LinkNotFound(name.to_string()))),
+    }
+  }
+
+  /// Bring the interface with the given name up.
+  pub fn set_up(&mut self, name: &str) -> Result<()> {
+    let index = self.link_index(name)?;
+    let mut message = Message::new(RTM_NEWLINK, 0);
+    let up = libc::IFF_UP as u32;
+    message.link(index, up, up);
+    self.request(message, &format!("bring up {}", name))?;
+    Ok(())
+  }
+
+  /// Create a bridge with the given name.
+  pub fn add_bridge(&mut self, name: &str) -> Result<()> {
+    let mut message = Message::new(RTM_NEWLINK, NLM_F_CREATE | NLM_F_EXCL);
+    message.link(0, 0, 0);
+    message.attr(IFLA_IFNAME, &ifname(name));
+    let info = message.begin(IFLA_LINKINFO);
+    message.attr(IFLA_INFO_KIND, b"bridge");
+    message.end(info);
+    self.request(message, &format!("create the bridge {}", name))?;
+    Ok(())
+  }
+
+  /// Create a veth pair. The peer is created right in the network namespace
+  /// of the given process, so its name only has to be unique there.
+  pub fn add_veth(&mut self, name: &str, peer: &str, peer_pid: Pid) -> Result<()> {
+    let mut message = Message::new(RTM_NEWLINK, NLM_F_CREATE | NLM_F_EXCL);
+    message.link(0, 0, 0);
+    message.attr(IFLA_IFNAME, &ifname(name));
+    let info = message.begin(IFLA_LINKINFO);
+    message.attr(IFLA_INFO_KIND, b"veth");
+    let data = message.begin(IFLA_INFO_DATA);
+    let peer_info = message.begin(VETH_INFO_PEER);
+    message.link(0, 0, 0);
+    message.attr(IFLA_IFNAME, &ifname(peer));
+    message.attr(IFLA_NET_NS_PID, &(i32::from(peer_pid) as u32).to_ne_bytes());
+    message.end(peer_info);
+    message.end(data);
+    message.end(info);
+    self.request(message, &format!("create the veth pair {}", name))?;
+    Ok(())
+  }
+
+  /// Attach the interface to a bridge.
+  pub fn set_master(&mut self, name: &str, master: &str) -> Result<()> {
+    let index = self.link_index(name)?;
+    let master_index = self.link_index(master)?;
+    let mut message = Message::new(RTM_NEWLINK, 0);
+    message.link(index, 0, 0);
+    message.attr(IFLA_MASTER, &(master_index as u32).to_ne_bytes());
+    self.request(message, &format!("attach {} to {}", name, master))?;
+    Ok(())
+  }
+
+  /// Delete the interface with the given name. Deleting one end of a veth
+  /// pair deletes the other one too.
+  pub fn delete_link(&mut self, name: &str) -> Result<()> {
+    let index = self.link_index(name)?;
+    let mut message = Message::new(RTM_DELLINK, 0);
+    message.link(index, 0, 0);
+    self.request(message, &format!("delete {}", name))?;
+    Ok(())
+  }
+
+  /// Assign an IPv4 address to the interface.
+  pub fn add_address(&mut self, name: &str, address: Ipv4Addr, prefix_len: u8) -> Result<()> {
+    let index = self.link_index(name)?;
+    let mut message = Message::new(RTM_NEWADDR, NLM_F_CREATE | NLM_F_EXCL);
+    message.address(index, prefix_len);
+    message.attr(IFA_LOCAL, &address.octets());
+    message.attr(IFA_ADDRESS, &address.octets());
+    self.request(message, &format!("assign {} to {}", address, name))?;
+    Ok(())
+  }
+
+  /// Route everything through the gateway, which is reachable on the given
+  /// interface.
+  pub fn add_default_route(&mut self, name: &str, gateway: Ipv4Addr) -> Result<()> {
+    let index = self.link_index(name)?;
+    let mut message = Message::new(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_EXCL);
+    message.route(0);
+    message.attr(RTA_GATEWAY, &gateway.octets());
+    message.attr(RTA_OIF, &(index as u32).to_ne_bytes());
+    self.request(message, &format!("add the default route via {}", gateway))?;
+    Ok(())
+  }
+}
+
+impl Drop for Netlink {
+  fn drop(&mut self) {
+    let _ = close(self.fd);
+  }
+}
@@ -0,0 +1,136 @@
+use super::ports::PortMapping;
+use super::NetworkError;
+use crate::Result;
+use std::env;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::process::{Command, Stdio};
+
+/// Table holding all of our rules, so that they don't mix with the rules of
+/// the host.
+const TABLE: &str = "ip container_rs";
+/// The command line tool of nftables, the rules are applied with it.
+const NFT: &str = "nft";
+
+/// Check that the nftables tool is installed. Bridge networks can't work
+/// without it, and it's better to know before anything is set up.
+pub fn check() -> Result<()> {
+  let found = env::var_os("PATH")
+    .is_some_and(|paths| env::split_paths(&paths).any(|dir| dir.join(NFT).is_file()));
+  if !found {
+    return Err(Box::new(NetworkError::NftablesNotFound));
+  }
+  Ok(())
+}
+
+/// Run an nftables script. The script is applied atomically, either all of
+/// its commands succeed or none of them.
+fn run(script: &str) -> Result<()> {
+  debug!("nftables script:\n{}", script);
+  let mut child = Command::new(NFT)
+    .arg("-f")
+    .arg("-")
+    .stdin(Stdio::piped())
+    .stdout(Stdio::null())
+    .stderr(Stdio::piped())
+    .spawn()
+    .map_err(|err| NetworkError::Nftables(format!("Failed to run nft: {}", err)))?;
+  child
+    .stdin
+    .take()
+    .expect("Failed to get the stdin of nft")
+    .write_all(script.as_bytes())?;
+
+  let output = child.wait_with_output()?;
+  if !output.status.success() {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    return Err(Box::new(NetworkError::Nftables(stderr.trim().to_string())));
+  }
+  Ok(())
+}
+
+/// Masquerade the traffic leaving the given subnets through any interface but
+/// their bridge. Each entry is a subnet in CIDR notation and its bridge. The
+/// rules replace the previous ones.
+pub fn set_masquerade(networks: &[(String, String)]) -> Result<()> {
+  let mut script = format!(
+    "add table {table}\n\
+     add chain {table} postrouting {{ type nat hook postrouting priority 100 ; }}\n\
+     flush chain {table} postrouting\n",
+    table = TABLE
+  );
+  for (subnet, bridge) in networks {
+    script += &format!(
+      "add rule {} postrouting ip saddr {} oifname != \"{}\" masquerade\n",
+      TABLE, subnet, bridge
+    );
+  }
+  run(&script)
+}
+
+/// Chains that forward the published ports. The ports published on every
+/// address are in the `ports` map, the others in the `address_ports` map, so
+/// that publishing and unpublishing a port only changes a map element.
+/// Connections to the loopback address are left to the userspace proxy, the
+/// kernel doesn't route them to other hosts.
+fn port_chains() -> String {
+  let mut script = format!(
+    "add table {table}\n\
+     add map {table} ports {{ type inet_proto . inet_service : ipv4_addr . inet_service ; }}\n\
+     add map {table} address_ports \
+     {{ type ipv4_addr . inet_proto . inet_service : ipv4_addr . inet_service ; }}\n",
+    table = TABLE
+  );
+  for (chain, hook, filter) in &[
+    ("prerouting", "prerouting", ""),
+    ("output", "output", "ip daddr != 127.0.0.0/8 "),
+  ] {
+    script += &format!(
+      "add chain {table} {chain} {{ type nat hook {hook} priority -100 ; }}\n\
+       flush chain {table} {chain}\n\
+       add rule {table} {chain} {filter}fib daddr type local \
+       dnat ip to ip daddr . meta l4proto . th dport map @address_ports\n\
+       add rule {table} {chain} {filter}fib daddr type local \
+       dnat ip to meta l4proto . th dport map @ports\n",
+      table = TABLE,
+      chain = chain,
+      hook = hook,
+      filter = filter
+    );
+  }
+  script
+}
+
+/// The map and the key of the element of a published port.
+fn port_element(port: &PortMapping) -> (&'static str, String) {
+  match port.host_ip {
+    Some(host_ip) => (
+      "address_ports",
+      format!("{} . {} . {}", host_ip, port.protocol, port.host_port),
+    ),
+    None => ("ports", format!("{} . {}", port.protocol, port.host_port)),
+  }
+}
+
+/// Forward the published ports to the container at the given address.
+pub fn add_ports(ports: &[PortMapping], address: Ipv4Addr) -> Result<()> {
+  let mut script = port_chains();
+  for port in ports {
+    let (map, key) = port_element(port);
+    script += &format!(
+      "add element {} {} {{ {} : {} . {} }}\n",
+      TABLE, map, key, address, port.container_port
+    );
+  }
+  run(&script)
+}
+
+/// Stop forwarding the published ports.
+pub fn delete_ports(ports: &[PortMapping]) -> Result<()> {
+  let mut script = String::new();
+  for port in ports {
+    let (map, key) = port_element(port);
+    script += &format!("delete element {} {} {{ {} }}\n", TABLE, map, key);
+  }
+  run(&script)
+}
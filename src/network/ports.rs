@@ -0,0 +1,262 @@
+use super::{nftables, NetworkError, NetworkMode};
+use crate::fs::get_ports_path;
+use crate::Result;
+use nix::fcntl::{flock, FlockArg};
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::net::Ipv4Addr;
+use std::os::unix::io::AsRawFd;
+use std::process;
+
+/// Serializes the reservations of the host ports.
+const LOCK_FILE: &str = "ports.lock";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+  Tcp,
+  Udp,
+}
+
+impl fmt::Display for Protocol {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Protocol::Tcp => write!(f, "tcp"),
+      Protocol::Udp => write!(f, "udp"),
+    }
+  }
+}
+
+/// A port of the container that is published on the host.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortMapping {
+  /// The host address the port is published on, every address if `None`.
+  pub host_ip: Option<Ipv4Addr>,
+  pub host_port: u16,
+  pub container_port: u16,
+  pub protocol: Protocol,
+}
+
+impl PortMapping {
+  /// Parse a `-p` value like `8080:80`, `127.0.0.1:8080:80` or `53:53/udp`.
+  pub fn parse(value: &str) -> Result<Self> {
+    let invalid = || Box::new(NetworkError::InvalidPort(value.to_string()));
+    let mut parts = value.splitn(2, '/');
+    let ports = parts.next().unwrap();
+    let protocol = match parts.next() {
+      None | Some("tcp") => Protocol::Tcp,
+      Some("udp") => Protocol::Udp,
+      Some(_) => return Err(invalid()),
+    };
+
+    let fields: Vec<&str> = ports.split(':').collect();
+    let (host_ip, host_port, container_port) = match fields.as_slice() {
+      [host_port, container_port] => (None, *host_port, *container_port),
+      [host_ip, host_port, container_port] => (
+        Some(host_ip.parse().map_err(|_| invalid())?),
+        *host_port,
+        *container_port,
+      ),
+      _ => return Err(invalid()),
+    };
+    let port = |port: &str| match port.parse::<u16>() {
+      Ok(port) if port > 0 => Ok(port),
+      _ => Err(invalid()),
+    };
+
+    Ok(PortMapping {
+      host_ip,
+      host_port: port(host_port)?,
+      container_port: port(container_port)?,
+      protocol,
+    })
+  }
+
+  /// Whether the port is published on the loopback address, which the
+  /// kernel doesn't route to the containers.
+  pub fn on_loopback(&self) -> bool {
+    match self.host_ip {
+      Some(host_ip) => host_ip.is_loopback(),
+      None => true,
+    }
+  }
+
+  /// Whether both mappings claim the same port of the host.
+  fn conflicts(&self, other: &PortMapping) -> bool {
+    self.host_port == other.host_port
+      && self.protocol == other.protocol
+      && (self.host_ip.is_none() || other.host_ip.is_none() || self.host_ip == other.host_ip)
+  }
+}
+
+impl fmt::Display for PortMapping {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if let Some(host_ip) = self.host_ip {
+      write!(f, "{}:", host_ip)?;
+    }
+    write!(
+      f,
+      "{}:{}/{}",
+      self.host_port, self.container_port, self.protocol
+    )
+  }
+}
+
+/// Get the published ports of the `run` subcommand, and reserve them. Only
+/// the containers of a bridge network have an address to forward them to. It
+/// fails if another container published any of the ports already.
+pub fn from_args(args: &clap::ArgMatches, mode: &NetworkMode) -> Result<Vec<PortMapping>> {
+  let values = match args.values_of("publish") {
+    Some(values) => values,
+    None => return Ok(vec![]),
+  };
+  if !matches!(mode, NetworkMode::Bridge(_)) {
+    return Err(Box::new(NetworkError::PortsWithoutBridge));
+  }
+
+  let ports = values.map(PortMapping::parse).collect::<Result<Vec<_>>>()?;
+  reserve(&ports)?;
+  Ok(ports)
+}
+
+/// A port of the host reserved for a container. It's held as long as the
+/// shim of the container lives, which covers the restarts of the container.
+#[derive(Debug, Serialize, Deserialize)]
+struct Reservation {
+  port: PortMapping,
+  shim_pid: i32,
+}
+
+impl Reservation {
+  fn is_stale(&self) -> bool {
+    kill(Pid::from_raw(self.shim_pid), None).is_err()
+  }
+
+  /// Every reservation is a file, named so that the ports that don't
+  /// conflict get different files.
+  fn file_name(&self) -> String {
+    let host_ip = match self.port.host_ip {
+      Some(host_ip) => host_ip.to_string(),
+      None => "any".to_string(),
+    };
+    format!("{}-{}-{}", self.port.protocol, self.port.host_port, host_ip)
+  }
+}
+
+/// Reserve the ports for the container of this shim. The reservations are
+/// checked and written under a lock, so that concurrent containers can't
+/// publish the same port.
+fn reserve(ports: &[PortMapping]) -> Result<()> {
+  let path = get_ports_path();
+  fs::create_dir_all(&path)?;
+  let lock = OpenOptions::new()
+    .write(true)
+    .create(true)
+    .truncate(false)
+    .open(path.join(LOCK_FILE))?;
+  flock(lock.as_raw_fd(), FlockArg::LockExclusive)?;
+
+  let mut reserved = vec![];
+  for entry in fs::read_dir(&path)? {
+    let file = entry?.path();
+    if file.ends_with(LOCK_FILE) {
+      continue;
+    }
+    match serde_json::from_slice::<Reservation>(&fs::read(&file)?) {
+      Ok(reservation) if !reservation.is_stale() => reserved.push(reservation.port),
+      _ => fs::remove_file(&file)?,
+    }
+  }
+  for port in ports {
+    if let Some(other) = reserved.iter().find(|other| port.conflicts(other)) {
+      return Err(Box::new(NetworkError::PortInUse(other.to_string())));
+    }
+    reserved.push(port.clone());
+  }
+
+  let shim_pid = process::id() as i32;
+  for port in ports {
+    let reservation = Reservation {
+      port: port.clone(),
+      shim_pid,
+    };
+    fs::write(
+      path.join(reservation.file_name()),
+      serde_json::to_vec(&reservation)?,
+    )?;
+  }
+  Ok(())
+}
+
+/// Forward the ports of the host to the container at the given address.
+pub fn publish(ports: &[PortMapping], address: Ipv4Addr) -> Result<()> {
+  if ports.is_empty() {
+    return Ok(());
+  }
+  nftables::add_ports(ports, address)
+}
+
+/// Remove the forwarding of the ports.
+pub fn unpublish(ports: &[PortMapping]) -> Result<()> {
+  if ports.is_empty() {
+    return Ok(());
+  }
+  nftables::delete_ports(ports)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_mappings() {
+    let port = PortMapping::parse("8080:80").unwrap();
+    assert_eq!(port.host_ip, None);
+    assert_eq!((port.host_port, port.container_port), (8080, 80));
+    assert_eq!(port.protocol, Protocol::Tcp);
+
+    let port = PortMapping::parse("127.0.0.1:53:53/udp").unwrap();
+    assert_eq!(port.host_ip, Some(Ipv4Addr::LOCALHOST));
+    assert_eq!(port.protocol, Protocol::Udp);
+    assert_eq!(port.to_string(), "127.0.0.1:53:53/udp");
+  }
+
+  #[test]
+  fn parse_ports_bounds() {
+    let port = PortMapping::parse("65535:1/tcp").unwrap();
+    assert_eq!((port.host_port, port.container_port), (65535, 1));
+    assert!(PortMapping::parse("65536:80").is_err());
+    assert!(PortMapping::parse("0:80").is_err());
+    assert!(PortMapping::parse("80:0").is_err());
+    assert!(PortMapping::parse("-1:80").is_err());
+  }
+
+  #[test]
+  fn parse_invalid_mappings() {
+    for value in &[
+      "",
+      "80",
+      "8080:80/sctp",
+      "8080:80/",
+      "localhost:8080:80",
+      "256.0.0.1:8080:80",
+      "1.2.3.4:8080:80:80",
+      "8080-8081:80",
+    ] {
+      assert!(PortMapping::parse(value).is_err(), "{}", value);
+    }
+  }
+
+  #[test]
+  fn conflicts() {
+    let port = |value| PortMapping::parse(value).unwrap();
+    assert!(port("8080:80").conflicts(&port("127.0.0.1:8080:81")));
+    assert!(port("10.0.0.1:8080:80").conflicts(&port("10.0.0.1:8080:81")));
+    assert!(!port("10.0.0.1:8080:80").conflicts(&port("10.0.0.2:8080:80")));
+    assert!(!port("8080:80").conflicts(&port("8080:80/udp")));
+    assert!(!port("8080:80").conflicts(&port("8081:80")));
+  }
+}
@@ -0,0 +1,133 @@
+use super::ports::{PortMapping, Protocol};
+use super::NetworkError;
+use crate::Result;
+use std::collections::hash_map::{Entry, HashMap};
+use std::io;
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How long a UDP client is remembered without any reply from the container.
+const UDP_TIMEOUT: Duration = Duration::from_secs(60);
+const UDP_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The ports this process forwards already. They stay when the shim restarts
+/// the container, its address stays leased to it until it's removed.
+static STARTED: Mutex<Vec<(Protocol, SocketAddr)>> = Mutex::new(Vec::new());
+
+/// Forward the ports published on the loopback address to the container at
+/// the given address from userspace. The proxies run on threads of this
+/// process, as long as it lives.
+pub fn start(ports: &[PortMapping], address: Ipv4Addr) -> Result<()> {
+  let mut started = STARTED.lock().unwrap();
+  for port in ports.iter().filter(|port| port.on_loopback()) {
+    let listen = SocketAddr::from((Ipv4Addr::LOCALHOST, port.host_port));
+    let target = SocketAddr::from((address, port.container_port));
+    if started.contains(&(port.protocol, listen)) {
+      continue;
+    }
+    let in_use = |_| NetworkError::PortInUse(port.to_string());
+    match port.protocol {
+      Protocol::Tcp => {
+        let listener = TcpListener::bind(listen).map_err(in_use)?;
+        thread::spawn(move || proxy_tcp(listener, target));
+      }
+      Protocol::Udp => {
+        let socket = UdpSocket::bind(listen).map_err(in_use)?;
+        thread::spawn(move || proxy_udp(socket, target));
+      }
+    }
+    started.push((port.protocol, listen));
+  }
+  Ok(())
+}
+
+fn proxy_tcp(listener: TcpListener, target: SocketAddr) {
+  for client in listener.incoming() {
+    match client {
+      Ok(client) => {
+        thread::spawn(move || {
+          if let Err(err) = forward_tcp(client, target) {
+            debug!("Failed to forward a connection to {}: {}", target, err);
+          }
+        });
+      }
+      Err(err) => warn!("Failed to accept a connection for {}: {}", target, err),
+    }
+  }
+}
+
+/// Copy the data both ways until both sides are done sending.
+fn forward_tcp(client: TcpStream, target: SocketAddr) -> io::Result<()> {
+  let upstream = TcpStream::connect(target)?;
+  let (mut client_read, mut client_write) = (client.try_clone()?, client);
+  let (mut upstream_read, mut upstream_write) = (upstream.try_clone()?, upstream);
+
+  let requests = thread::spawn(move || {
+    let _ = io::copy(&mut client_read, &mut upstream_write);
+    let _ = upstream_write.shutdown(Shutdown::Write);
+  });
+  let _ = io::copy(&mut upstream_read, &mut client_write);
+  let _ = client_write.shutdown(Shutdown::Write);
+  let _ = requests.join();
+  Ok(())
+}
+
+/// Forward the datagrams of every client through a socket of its own, so
+/// that the replies of the container can be sent back to the right client.
+fn proxy_udp(socket: UdpSocket, target: SocketAddr) {
+  let sessions: Arc<Mutex<HashMap<SocketAddr, UdpSocket>>> = Arc::new(Mutex::new(HashMap::new()));
+  let mut buf = vec![0; UDP_BUFFER_SIZE];
+  loop {
+    let (len, client) = match socket.recv_from(&mut buf) {
+      Ok(received) => received,
+      Err(err) => {
+        warn!("Failed to receive a datagram for {}: {}", target, err);
+        continue;
+      }
+    };
+
+    let mut sessions_guard = sessions.lock().unwrap();
+    let upstream = match sessions_guard.entry(client) {
+      Entry::Occupied(entry) => entry.into_mut(),
+      Entry::Vacant(entry) => match udp_session(&socket, client, target, &sessions) {
+        Ok(upstream) => entry.insert(upstream),
+        Err(err) => {
+          debug!("Failed to forward a datagram to {}: {}", target, err);
+          continue;
+        }
+      },
+    };
+    if let Err(err) = upstream.send(&buf[..len]) {
+      debug!("Failed to forward a datagram to {}: {}", target, err);
+    }
+  }
+}
+
+/// Open the socket of a client, and send the replies back to it from another
+/// thread until the container stops replying.
+fn udp_session(
+  socket: &UdpSocket,
+  client: SocketAddr,
+  target: SocketAddr,
+  sessions: &Arc<Mutex<HashMap<SocketAddr, UdpSocket>>>,
+) -> io::Result<UdpSocket> {
+  let upstream = UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))?;
+  upstream.connect(target)?;
+  upstream.set_read_timeout(Some(UDP_TIMEOUT))?;
+
+  let replies = upstream.try_clone()?;
+  let socket = socket.try_clone()?;
+  let sessions = Arc::clone(sessions);
+  thread::spawn(move || {
+    let mut buf = vec![0; UDP_BUFFER_SIZE];
+    while let Ok(len) = replies.recv(&mut buf) {
+      if socket.send_to(&buf[..len], client).is_err() {
+        break;
+      }
+    }
+    sessions.lock().unwrap().remove(&client);
+  });
+  Ok(upstream)
+}
@@ -0,0 +1,34 @@
+use crate::cgroups;
+use crate::state::{self, StateError, Status};
+use crate::Result;
+
+/// Main entry for the pause subcommand. Freezes every process of a running
+/// container.
+pub fn pause(args: &clap::ArgMatches<'static>) -> Result<()> {
+  let id = state::find_running(args.value_of("id").unwrap())?.id;
+  state::update_running(&id, |state| {
+    if state.status == Status::Paused {
+      return Err(Box::new(StateError::Paused(state.id.clone())));
+    }
+    cgroups::new(&state.id)?.freeze()?;
+    state.status = Status::Paused;
+    Ok(())
+  })?;
+  info!("Paused the container {}", id);
+  Ok(())
+}
+
+/// Main entry for the unpause subcommand. Thaws a paused container.
+pub fn unpause(args: &clap::ArgMatches<'static>) -> Result<()> {
+  let id = state::find_running(args.value_of("id").unwrap())?.id;
+  state::update_running(&id, |state| {
+    if state.status != Status::Paused {
+      return Err(Box::new(StateError::NotPaused(state.id.clone())));
+    }
+    cgroups::new(&state.id)?.thaw()?;
+    state.status = Status::Running;
+    Ok(())
+  })?;
+  info!("Unpaused the container {}", id);
+  Ok(())
+}
@@ -1,4 +1,5 @@
 use crate::fs::get_image_path;
+use crate::images::{CONFIG_FILE, MANIFEST_FILE};
 use crate::registries::Registry;
 use crate::Result;
 use async_trait::async_trait;
@@ -97,11 +98,12 @@ impl Registry for DockerRegistry {
 
     let tmp_dir = Builder::new().prefix("container_rs").tempdir()?;
     let manifest = self.get_manifest().await?;
+    let config = self.get_config(&manifest).await?;
     let layers = self
       .download_docker_image_layers(&manifest, &tmp_dir)
       .await?;
 
-    self.copy_to_images_dir(layers).await?;
+    self.copy_to_images_dir(layers, &manifest, &config).await?;
     Ok(())
   }
 }
@@ -148,6 +150,28 @@ impl DockerRegistry {
     Ok(res)
   }
 
+  async fn get_config(&self, manifest: &DockerManifestResult) -> Result<Vec<u8>> {
+    info!("Getting the image config from docker registry.");
+    let url = format!(
+      "{registry}/{image}/blobs/{digest}",
+      registry = self.registry_url,
+      image = self.image_name.as_ref().unwrap(),
+      digest = manifest.config.digest
+    );
+
+    let client = reqwest::Client::new();
+    let res = client
+      .get(&url)
+      .bearer_auth(self.auth_token.as_ref().unwrap())
+      .send()
+      .await?
+      .bytes()
+      .await?;
+
+    info!("Got the image config.");
+    Ok(res.to_vec())
+  }
+
   async fn download_docker_image_layers(
     &self,
     manifest: &DockerManifestResult,
@@ -205,7 +229,12 @@ impl DockerRegistry {
     Ok(file_objects.into_iter().map(|x| x.0).collect())
   }
 
-  async fn copy_to_images_dir(&self, layers: Vec<PathBuf>) -> Result<()> {
+  async fn copy_to_images_dir(
+    &self,
+    layers: Vec<PathBuf>,
+    manifest: &DockerManifestResult,
+    config: &[u8],
+  ) -> Result<()> {
     info!("Starting to unwrap the docker image layers");
     let image_path = get_image_path(self.image_name.as_ref().unwrap());
     if !image_path.exists() {
@@ -221,6 +250,12 @@ impl DockerRegistry {
       tokio::fs::copy(&layer, &dest_path).await?;
     }
 
+    fs::write(
+      image_path.join(MANIFEST_FILE),
+      serde_json::to_vec_pretty(manifest)?,
+    )?;
+    fs::write(image_path.join(CONFIG_FILE), config)?;
+
     info!("Succesfully copied all the image layers");
     Ok(())
   }
@@ -0,0 +1,22 @@
+use crate::network;
+use crate::state::{self, StateError};
+use crate::Result;
+
+/// Main entry for the rm subcommand. Removes the recorded state and the logs
+/// of containers that are not running, and gives their address back.
+pub fn remove(args: &clap::ArgMatches<'static>) -> Result<()> {
+  for id in args.values_of("id").unwrap() {
+    let state = state::find(id)?;
+    if state.is_running() {
+      return Err(Box::new(StateError::Running(state.id)));
+    }
+    if state.is_restarting() {
+      return Err(Box::new(StateError::Restarting(state.id)));
+    }
+
+    network::release(&state)?;
+    state.remove()?;
+    println!("{}", state.id);
+  }
+  Ok(())
+}
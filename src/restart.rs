@@ -0,0 +1,217 @@
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The delay before the first restart of a container.
+const INITIAL_DELAY: Duration = Duration::from_millis(100);
+/// The delay doubles after every restart, up to this.
+const MAX_DELAY: Duration = Duration::from_secs(60);
+/// A container that ran for this long is considered healthy again, and the
+/// delay starts over.
+const RESET_UPTIME: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum RestartError {
+  InvalidPolicy(String),
+  WithAutoRemove,
+}
+
+impl fmt::Display for RestartError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RestartError::InvalidPolicy(policy) => write!(
+        f,
+        "Invalid restart policy {}, expected no, on-failure[:max], always or unless-stopped",
+        policy
+      ),
+      RestartError::WithAutoRemove => write!(f, "--restart and --rm can't be used together"),
+    }
+  }
+}
+
+impl Error for RestartError {}
+
+/// When the supervisor of a container restarts it after it exits. A
+/// container that got stopped with the stop command is never restarted.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum RestartPolicy {
+  #[default]
+  No,
+  /// Restart the container if it exits with a non-zero code, at most the
+  /// given number of times.
+  OnFailure(Option<u32>),
+  Always,
+  /// Like `Always`. The two only differ for daemons that restart containers
+  /// when they start, we have none.
+  UnlessStopped,
+}
+
+impl RestartPolicy {
+  /// Whether a container that exited with the code gets restarted, after it
+  /// got restarted `count` times already.
+  pub fn should_restart(&self, code: i32, count: u32) -> bool {
+    match self {
+      RestartPolicy::No => false,
+      RestartPolicy::OnFailure(max) => code != 0 && !matches!(max, Some(max) if count >= *max),
+      RestartPolicy::Always | RestartPolicy::UnlessStopped => true,
+    }
+  }
+}
+
+impl FromStr for RestartPolicy {
+  type Err = RestartError;
+
+  fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+    let invalid = || RestartError::InvalidPolicy(value.to_string());
+    match value.split_once(':') {
+      Some(("on-failure", max)) => Ok(RestartPolicy::OnFailure(Some(
+        max.parse().map_err(|_| invalid())?,
+      ))),
+      Some(_) => Err(invalid()),
+      None => match value {
+        "no" => Ok(RestartPolicy::No),
+        "on-failure" => Ok(RestartPolicy::OnFailure(None)),
+        "always" => Ok(RestartPolicy::Always),
+        "unless-stopped" => Ok(RestartPolicy::UnlessStopped),
+        _ => Err(invalid()),
+      },
+    }
+  }
+}
+
+impl TryFrom<String> for RestartPolicy {
+  type Error = RestartError;
+
+  fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+    value.parse()
+  }
+}
+
+impl From<RestartPolicy> for String {
+  fn from(policy: RestartPolicy) -> Self {
+    policy.to_string()
+  }
+}
+
+impl fmt::Display for RestartPolicy {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RestartPolicy::No => write!(f, "no"),
+      RestartPolicy::OnFailure(None) => write!(f, "on-failure"),
+      RestartPolicy::OnFailure(Some(max)) => write!(f, "on-failure:{}", max),
+      RestartPolicy::Always => write!(f, "always"),
+      RestartPolicy::UnlessStopped => write!(f, "unless-stopped"),
+    }
+  }
+}
+
+/// Parse the `--restart` argument of the `run` subcommand.
+pub fn from_args(args: &clap::ArgMatches) -> Result<RestartPolicy> {
+  let policy = match args.value_of("restart") {
+    Some(policy) => policy.parse()?,
+    None => RestartPolicy::No,
+  };
+  // The state of the container is gone once it exits with `--rm`.
+  if policy != RestartPolicy::No && args.is_present("rm") {
+    return Err(Box::new(RestartError::WithAutoRemove));
+  }
+  Ok(policy)
+}
+
+/// The delay before the next restart of a container. It doubles after every
+/// restart, and starts over once the container ran for a while.
+pub struct Backoff {
+  delay: Duration,
+}
+
+impl Default for Backoff {
+  fn default() -> Self {
+    Backoff {
+      delay: INITIAL_DELAY,
+    }
+  }
+}
+
+impl Backoff {
+  /// The delay before restarting a container that ran for the given time.
+  pub fn next(&mut self, uptime: Duration) -> Duration {
+    if uptime >= RESET_UPTIME {
+      self.delay = INITIAL_DELAY;
+    }
+    let delay = self.delay;
+    self.delay = (self.delay * 2).min(MAX_DELAY);
+    delay
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_policies() {
+    assert_eq!("no".parse::<RestartPolicy>().unwrap(), RestartPolicy::No);
+    assert_eq!(
+      "always".parse::<RestartPolicy>().unwrap(),
+      RestartPolicy::Always
+    );
+    assert_eq!(
+      "unless-stopped".parse::<RestartPolicy>().unwrap(),
+      RestartPolicy::UnlessStopped
+    );
+    assert_eq!(
+      "on-failure".parse::<RestartPolicy>().unwrap(),
+      RestartPolicy::OnFailure(None)
+    );
+    assert_eq!(
+      "on-failure:3".parse::<RestartPolicy>().unwrap(),
+      RestartPolicy::OnFailure(Some(3))
+    );
+  }
+
+  #[test]
+  fn parse_retries_bounds() {
+    assert_eq!(
+      "on-failure:0".parse::<RestartPolicy>().unwrap(),
+      RestartPolicy::OnFailure(Some(0))
+    );
+    assert_eq!(
+      "on-failure:4294967295".parse::<RestartPolicy>().unwrap(),
+      RestartPolicy::OnFailure(Some(u32::MAX))
+    );
+    assert!("on-failure:4294967296".parse::<RestartPolicy>().is_err());
+    assert!("on-failure:-1".parse::<RestartPolicy>().is_err());
+  }
+
+  #[test]
+  fn parse_invalid_policies() {
+    for value in &[
+      "",
+      "never",
+      "Always",
+      "always:1",
+      "on-failure:",
+      "on-failure:1:2",
+    ] {
+      assert!(value.parse::<RestartPolicy>().is_err(), "{}", value);
+    }
+  }
+
+  #[test]
+  fn policies_round_trip() {
+    for value in &[
+      "no",
+      "on-failure",
+      "on-failure:5",
+      "always",
+      "unless-stopped",
+    ] {
+      assert_eq!(value.parse::<RestartPolicy>().unwrap().to_string(), *value);
+    }
+  }
+}
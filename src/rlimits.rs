@@ -0,0 +1,233 @@
+use crate::Result;
+use nix::libc;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+
+/// Every resource `setrlimit` knows about, with the short name that `--ulimit`
+/// accepts, the name used by the OCI runtime spec and its constant. The type
+/// of the constants depends on the C library, they are stored as an `i32`.
+const RESOURCES: &[(&str, &str, i32)] = &[
+  ("as", "RLIMIT_AS", libc::RLIMIT_AS as i32),
+  ("core", "RLIMIT_CORE", libc::RLIMIT_CORE as i32),
+  ("cpu", "RLIMIT_CPU", libc::RLIMIT_CPU as i32),
+  ("data", "RLIMIT_DATA", libc::RLIMIT_DATA as i32),
+  ("fsize", "RLIMIT_FSIZE", libc::RLIMIT_FSIZE as i32),
+  ("locks", "RLIMIT_LOCKS", libc::RLIMIT_LOCKS as i32),
+  ("memlock", "RLIMIT_MEMLOCK", libc::RLIMIT_MEMLOCK as i32),
+  ("msgqueue", "RLIMIT_MSGQUEUE", libc::RLIMIT_MSGQUEUE as i32),
+  ("nice", "RLIMIT_NICE", libc::RLIMIT_NICE as i32),
+  ("nofile", "RLIMIT_NOFILE", libc::RLIMIT_NOFILE as i32),
+  ("nproc", "RLIMIT_NPROC", libc::RLIMIT_NPROC as i32),
+  ("rss", "RLIMIT_RSS", libc::RLIMIT_RSS as i32),
+  ("rtprio", "RLIMIT_RTPRIO", libc::RLIMIT_RTPRIO as i32),
+  ("rttime", "RLIMIT_RTTIME", libc::RLIMIT_RTTIME as i32),
+  (
+    "sigpending",
+    "RLIMIT_SIGPENDING",
+    libc::RLIMIT_SIGPENDING as i32,
+  ),
+  ("stack", "RLIMIT_STACK", libc::RLIMIT_STACK as i32),
+];
+
+#[derive(Debug)]
+pub enum RlimitError {
+  UnknownResource(String),
+  InvalidValue(String),
+  SoftAboveHard(String),
+}
+
+impl fmt::Display for RlimitError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RlimitError::UnknownResource(name) => write!(f, "Unknown rlimit resource: {}", name),
+      RlimitError::InvalidValue(value) => write!(f, "Invalid rlimit value: {}", value),
+      RlimitError::SoftAboveHard(name) => {
+        write!(f, "Soft limit is greater than the hard limit for {}", name)
+      }
+    }
+  }
+}
+
+impl Error for RlimitError {}
+
+/// A single resource limit. Serialized the same way as the OCI runtime spec's
+/// `process.rlimits` entries so the two can be used interchangeably.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rlimit {
+  #[serde(rename = "type")]
+  pub resource: String,
+  pub soft: u64,
+  pub hard: u64,
+}
+
+impl Rlimit {
+  /// Parse a `--ulimit` value like `nofile=1024:2048` or `core=0`. A single
+  /// value sets both the soft and the hard limit, `-1` and `unlimited` mean
+  /// no limit.
+  pub fn parse(ulimit: &str) -> Result<Self> {
+    let mut parts = ulimit.splitn(2, '=');
+    let name = parts.next().unwrap_or_default();
+    let values = parts
+      .next()
+      .ok_or_else(|| RlimitError::InvalidValue(ulimit.to_string()))?;
+
+    let resource = RESOURCES
+      .iter()
+      .find(|(short, _, _)| *short == name)
+      .map(|(_, oci, _)| oci.to_string())
+      .ok_or_else(|| RlimitError::UnknownResource(name.to_string()))?;
+
+    let mut values = values.splitn(2, ':');
+    let soft = parse_limit(values.next().unwrap_or_default())?;
+    let hard = match values.next() {
+      Some(hard) => parse_limit(hard)?,
+      None => soft,
+    };
+
+    let rlimit = Rlimit {
+      resource,
+      soft,
+      hard,
+    };
+    rlimit.validate()?;
+    Ok(rlimit)
+  }
+
+  fn validate(&self) -> Result<()> {
+    resource_id(&self.resource)?;
+    if self.soft > self.hard {
+      return Err(Box::new(RlimitError::SoftAboveHard(self.resource.clone())));
+    }
+    Ok(())
+  }
+
+  /// Apply the limit to the calling process. This is called by the container
+  /// init right before it executes the user command.
+  pub fn apply(&self) -> Result<()> {
+    let limit = libc::rlimit {
+      rlim_cur: self.soft as libc::rlim_t,
+      rlim_max: self.hard as libc::rlim_t,
+    };
+    let resource = resource_id(&self.resource)?;
+    if unsafe { libc::setrlimit(resource as _, &limit) } != 0 {
+      return Err(Box::new(std::io::Error::last_os_error()));
+    }
+    Ok(())
+  }
+}
+
+fn parse_limit(value: &str) -> Result<u64> {
+  match value {
+    "-1" | "unlimited" => Ok(libc::RLIM_INFINITY),
+    _ => Ok(
+      value
+        .parse()
+        .map_err(|_| RlimitError::InvalidValue(value.to_string()))?,
+    ),
+  }
+}
+
+/// Map the OCI name of a resource to the constant `setrlimit` expects.
+fn resource_id(resource: &str) -> Result<i32> {
+  RESOURCES
+    .iter()
+    .find(|(_, oci, _)| *oci == resource)
+    .map(|(_, _, id)| *id)
+    .ok_or_else(|| Box::new(RlimitError::UnknownResource(resource.to_string())).into())
+}
+
+#[derive(Deserialize)]
+struct OciConfig {
+  process: Option<OciProcess>,
+}
+
+#[derive(Deserialize)]
+struct OciProcess {
+  #[serde(default)]
+  rlimits: Vec<Rlimit>,
+}
+
+/// Collect the rlimits of the `run` subcommand. Limits from the OCI config are
+/// read first so that `--ulimit` can override them.
+pub fn from_args(args: &clap::ArgMatches) -> Result<Vec<Rlimit>> {
+  let mut rlimits: Vec<Rlimit> = vec![];
+
+  if let Some(config) = args.value_of("oci-config") {
+    let config: OciConfig = serde_json::from_slice(&fs::read(config)?)?;
+    for rlimit in config.process.map(|p| p.rlimits).unwrap_or_default() {
+      rlimit.validate()?;
+      rlimits.push(rlimit);
+    }
+  }
+
+  if let Some(ulimits) = args.values_of("ulimit") {
+    for ulimit in ulimits {
+      let rlimit = Rlimit::parse(ulimit)?;
+      rlimits.retain(|r| r.resource != rlimit.resource);
+      rlimits.push(rlimit);
+    }
+  }
+
+  Ok(rlimits)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_limits() {
+    let rlimit = Rlimit::parse("nofile=1024:2048").unwrap();
+    assert_eq!(rlimit.resource, "RLIMIT_NOFILE");
+    assert_eq!((rlimit.soft, rlimit.hard), (1024, 2048));
+
+    let rlimit = Rlimit::parse("core=0").unwrap();
+    assert_eq!((rlimit.soft, rlimit.hard), (0, 0));
+  }
+
+  #[test]
+  fn parse_unlimited() {
+    let rlimit = Rlimit::parse("memlock=-1").unwrap();
+    assert_eq!(
+      (rlimit.soft, rlimit.hard),
+      (libc::RLIM_INFINITY, libc::RLIM_INFINITY)
+    );
+
+    let rlimit = Rlimit::parse("stack=8388608:unlimited").unwrap();
+    assert_eq!((rlimit.soft, rlimit.hard), (8388608, libc::RLIM_INFINITY));
+    assert!(Rlimit::parse("stack=unlimited:8388608").is_err());
+  }
+
+  #[test]
+  fn parse_bounds() {
+    let rlimit = Rlimit::parse("fsize=18446744073709551615").unwrap();
+    assert_eq!(rlimit.soft, u64::MAX);
+    assert!(Rlimit::parse("fsize=18446744073709551616").is_err());
+    assert!(Rlimit::parse("nofile=2048:1024").is_err());
+    assert!(Rlimit::parse("nofile=-2").is_err());
+  }
+
+  #[test]
+  fn parse_invalid() {
+    for ulimit in &[
+      "nofile",
+      "nofile=",
+      "nofile=1:",
+      "nofile=:1",
+      "files=1",
+      "=1",
+    ] {
+      assert!(Rlimit::parse(ulimit).is_err(), "{}", ulimit);
+    }
+  }
+
+  #[test]
+  fn resources_have_ids() {
+    for (_, oci, id) in RESOURCES {
+      assert_eq!(resource_id(oci).unwrap(), *id);
+    }
+    assert!(resource_id("RLIMIT_FOO").is_err());
+  }
+}
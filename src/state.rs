@@ -0,0 +1,321 @@
+use crate::cgroups::Resources;
+use crate::devices::{Device, DeviceRule};
+use crate::etc::EtcConfig;
+use crate::fs::{get_container_path, get_containers_path};
+use crate::health::HealthConfig;
+use crate::logs::LogConfig;
+use crate::network::{Endpoint, NetworkMode, PortMapping};
+use crate::restart::RestartPolicy;
+use crate::rlimits::Rlimit;
+use crate::Result;
+use nix::fcntl::{flock, FlockArg};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::WaitStatus;
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::net::Ipv4Addr;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+const STATE_FILE: &str = "state.json";
+/// Serializes the changes to the state of a container.
+const LOCK_FILE: &str = "state.lock";
+
+#[derive(Debug)]
+pub enum StateError {
+  NotFound(String),
+  Ambiguous(String),
+  NotRunning(String),
+  Running(String),
+  Paused(String),
+  NotPaused(String),
+  Restarting(String),
+}
+
+impl fmt::Display for StateError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      StateError::NotFound(id) => write!(f, "No such container: {}", id),
+      StateError::Ambiguous(id) => write!(f, "Multiple containers match the id {}", id),
+      StateError::NotRunning(id) => write!(f, "Container {} is not running", id),
+      StateError::Running(id) => write!(f, "Container {} is still running", id),
+      StateError::Paused(id) => write!(f, "Container {} is paused", id),
+      StateError::NotPaused(id) => write!(f, "Container {} is not paused", id),
+      StateError::Restarting(id) => write!(f, "Container {} is restarting, stop it first", id),
+    }
+  }
+}
+
+impl Error for StateError {}
+
+/// The lifecycle status of a container.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+  #[default]
+  Running,
+  /// Every process of the container is frozen by the cgroup freezer.
+  Paused,
+  /// The init process of the container exited, and its shim cleaned up
+  /// after it.
+  Exited,
+  /// The container exited, and its shim waits to restart it.
+  Restarting,
+}
+
+/// Everything we know about a container, persisted as JSON under
+/// `/var/container_rs/containers/<id>/state.json` so that other subcommands
+/// can find it later.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContainerState {
+  pub id: String,
+  pub pid: i32,
+  /// The pid of the shim that supervises the container.
+  #[serde(default)]
+  pub shim_pid: i32,
+  pub image: String,
+  pub command: Vec<String>,
+  /// Whether the command runs on a pseudo-terminal.
+  #[serde(default)]
+  pub tty: bool,
+  /// Whether the command reads the input of the user.
+  #[serde(default)]
+  pub interactive: bool,
+  /// Whether the command runs under the built-in init.
+  #[serde(default)]
+  pub init: bool,
+  /// Where the output of the command is logged.
+  #[serde(default)]
+  pub log: LogConfig,
+  pub created: u64,
+  /// When the init process of the container started and exited.
+  #[serde(default)]
+  pub started: Option<u64>,
+  #[serde(default)]
+  pub finished: Option<u64>,
+  #[serde(default)]
+  pub status: Status,
+  /// How the init process exited the last time, once it did.
+  #[serde(default)]
+  pub exit_status: Option<ExitStatus>,
+  /// When the shim restarts the container after it exits.
+  #[serde(default)]
+  pub restart_policy: RestartPolicy,
+  /// How many times the container got restarted.
+  #[serde(default)]
+  pub restart_count: u32,
+  /// Whether the container got stopped with the stop command, it isn't
+  /// restarted then.
+  #[serde(default)]
+  pub manually_stopped: bool,
+  /// Whether the state is removed once the container exits.
+  #[serde(default)]
+  pub auto_remove: bool,
+  /// Name of the signal that stops the container gracefully.
+  #[serde(default = "default_stop_signal")]
+  pub stop_signal: String,
+  /// How the shim checks that the container works.
+  #[serde(default)]
+  pub healthcheck: Option<HealthConfig>,
+  pub rootfs: PathBuf,
+  #[serde(default)]
+  pub rlimits: Vec<Rlimit>,
+  #[serde(default)]
+  pub resources: Resources,
+  /// Device nodes of the host that are created in the container.
+  #[serde(default)]
+  pub devices: Vec<Device>,
+  /// Devices the container is allowed to access.
+  #[serde(default)]
+  pub device_rules: Vec<DeviceRule>,
+  #[serde(default)]
+  pub network: NetworkMode,
+  /// The address requested for the container on its network.
+  #[serde(default)]
+  pub ip: Option<Ipv4Addr>,
+  /// Ports of the container published on the host.
+  #[serde(default)]
+  pub ports: Vec<PortMapping>,
+  /// The hostname and the name resolution of the container.
+  #[serde(flatten)]
+  pub etc: EtcConfig,
+  /// The connection to the bridge, for containers of the bridge network.
+  #[serde(default)]
+  pub endpoint: Option<Endpoint>,
+}
+
+fn default_stop_signal() -> String {
+  Signal::SIGTERM.as_str().to_string()
+}
+
+impl ContainerState {
+  /// Write the state to disk, replacing the previous one.
+  pub fn save(&self) -> Result<()> {
+    let path = get_container_path(&self.id);
+    fs::create_dir_all(&path)?;
+    // Write into a temporary file first so readers never see a half written
+    // state.
+    let tmp_path = path.join(format!("{}.tmp", STATE_FILE));
+    fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+    fs::rename(tmp_path, path.join(STATE_FILE))?;
+    Ok(())
+  }
+
+  /// Load the state of the container with the given id.
+  pub fn load(id: &str) -> Result<Self> {
+    let path = get_container_path(id).join(STATE_FILE);
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+  }
+
+  /// Whether the init process of the container is still alive. The pid of
+  /// an exited container may belong to another process by now.
+  pub fn is_running(&self) -> bool {
+    matches!(self.status, Status::Running | Status::Paused)
+      && kill(Pid::from_raw(self.pid), None).is_ok()
+  }
+
+  /// Whether the shim of the container waits to restart it. The shim may be
+  /// gone after a crash.
+  pub fn is_restarting(&self) -> bool {
+    self.status == Status::Restarting && self.has_shim()
+  }
+
+  /// Whether the shim of the container is still alive, and records its exit.
+  pub fn has_shim(&self) -> bool {
+    self.shim_pid > 0 && kill(Pid::from_raw(self.shim_pid), None).is_ok()
+  }
+
+  /// Remove the state directory of the container.
+  pub fn remove(&self) -> Result<()> {
+    let path = get_container_path(&self.id);
+    if path.exists() {
+      fs::remove_dir_all(path)?;
+    }
+    Ok(())
+  }
+}
+
+/// Load the state of every known container.
+pub fn list() -> Result<Vec<ContainerState>> {
+  let path = get_containers_path();
+  if !path.exists() {
+    return Ok(vec![]);
+  }
+
+  let mut containers = vec![];
+  for entry in fs::read_dir(path)? {
+    let id = entry?.file_name().into_string().unwrap();
+    match ContainerState::load(&id) {
+      Ok(state) => containers.push(state),
+      Err(err) => warn!("Skipping the container {}: {}", id, err),
+    }
+  }
+  Ok(containers)
+}
+
+/// Find a container by its full id or a unique prefix of it.
+pub fn find(id: &str) -> Result<ContainerState> {
+  let mut matches: Vec<ContainerState> = list()?
+    .into_iter()
+    .filter(|state| state.id.starts_with(id))
+    .collect();
+
+  match matches.len() {
+    0 => Err(Box::new(StateError::NotFound(id.to_string()))),
+    1 => Ok(matches.remove(0)),
+    _ => Err(Box::new(StateError::Ambiguous(id.to_string()))),
+  }
+}
+
+/// Find a container like `find`, but fail if it's not running.
+pub fn find_running(id: &str) -> Result<ContainerState> {
+  let state = find(id)?;
+  if !state.is_running() {
+    return Err(Box::new(StateError::NotRunning(state.id)));
+  }
+  Ok(state)
+}
+
+/// Take the lock of the state of a container. The subcommands and the shim
+/// change the state under it, so that they don't overwrite each other's
+/// changes. It's held until the file is dropped.
+pub fn lock(id: &str) -> Result<File> {
+  let file = OpenOptions::new()
+    .write(true)
+    .create(true)
+    .truncate(false)
+    .open(get_container_path(id).join(LOCK_FILE))?;
+  flock(file.as_raw_fd(), FlockArg::LockExclusive)?;
+  Ok(file)
+}
+
+/// Change the state of a running container from its latest version on disk,
+/// under its lock. The change fails if the container exited meanwhile.
+pub fn update_running<F>(id: &str, change: F) -> Result<()>
+where
+  F: FnOnce(&mut ContainerState) -> Result<()>,
+{
+  let _lock = lock(id)?;
+  let mut state = ContainerState::load(id)?;
+  if !state.is_running() {
+    return Err(Box::new(StateError::NotRunning(state.id)));
+  }
+  change(&mut state)?;
+  state.save()
+}
+
+/// How the init process of a container exited.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExitStatus {
+  /// Exit code, if the process exited by itself.
+  pub code: Option<i32>,
+  /// Name of the signal that killed the process.
+  pub signal: Option<String>,
+  /// Whether the OOM killer killed any process of the container.
+  pub oom_killed: bool,
+}
+
+impl ExitStatus {
+  pub fn new(status: WaitStatus, oom_killed: bool) -> Self {
+    let (code, signal) = match status {
+      WaitStatus::Exited(_, code) => (Some(code), None),
+      WaitStatus::Signaled(_, signal, _) => (None, Some(signal.as_str().to_string())),
+      _ => (None, None),
+    };
+    ExitStatus {
+      code,
+      signal,
+      oom_killed,
+    }
+  }
+
+  /// The exit code a shell reports for the process, 128 plus the signal if
+  /// it got killed.
+  pub fn exit_code(&self) -> i32 {
+    match (self.code, &self.signal) {
+      (Some(code), _) => code,
+      (None, Some(signal)) => match signal.parse::<Signal>() {
+        Ok(signal) => 128 + signal as i32,
+        Err(_) => 128,
+      },
+      (None, None) => 0,
+    }
+  }
+}
+
+impl fmt::Display for ExitStatus {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match (self.code, &self.signal) {
+      (Some(code), _) => write!(f, "exited with code {}", code)?,
+      (None, Some(signal)) => write!(f, "killed by {}", signal)?,
+      (None, None) => write!(f, "exited")?,
+    }
+    if self.oom_killed {
+      write!(f, " (OOMKilled)")?;
+    }
+    Ok(())
+  }
+}
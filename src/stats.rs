@@ -0,0 +1,158 @@
+use crate::cgroups::{self, Cgroup, Stats};
+use crate::state::{self, ContainerState};
+use crate::Result;
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A container that we collect the statistics of, with its previous sample
+/// to compute the rates from.
+struct Sample {
+  state: ContainerState,
+  cgroup: Box<dyn Cgroup>,
+  stats: Stats,
+  time: Instant,
+}
+
+/// Main entry for the stats subcommand. Prints the resource usage of the
+/// given containers, or of all the running containers.
+pub fn stats(args: &clap::ArgMatches<'static>) -> Result<()> {
+  let containers = match args.values_of("id") {
+    Some(ids) => ids.map(state::find_running).collect::<Result<Vec<_>>>()?,
+    None => state::list()?
+      .into_iter()
+      .filter(ContainerState::is_running)
+      .collect(),
+  };
+
+  let mut samples = vec![];
+  for state in containers {
+    let cgroup = cgroups::new(&state.id)?;
+    samples.push(Sample {
+      stats: cgroup.stats()?,
+      time: Instant::now(),
+      state,
+      cgroup,
+    });
+  }
+
+  if args.is_present("json") {
+    let stats: Vec<_> = samples
+      .iter()
+      .map(|sample| serde_json::json!({ "id": sample.state.id, "stats": sample.stats }))
+      .collect();
+    println!("{}", serde_json::to_string_pretty(&stats)?);
+    return Ok(());
+  }
+
+  let stream = !args.is_present("no-stream");
+  loop {
+    // The CPU usage is a rate, wait for a second sample before printing.
+    thread::sleep(REFRESH_INTERVAL);
+    samples.retain(|sample| sample.state.is_running());
+
+    let mut stdout = io::stdout();
+    if stream {
+      // Clear the screen and move the cursor to the top.
+      write!(&mut stdout, "\x1b[2J\x1b[H")?;
+    }
+    writeln!(
+      &mut stdout,
+      "{:<12}  {:>7}  {:>21}  {:>7}  {:>6}  {:>21}  {:>9}  {:>17}",
+      "CONTAINER ID",
+      "CPU %",
+      "MEM USAGE / LIMIT",
+      "MEM %",
+      "PIDS",
+      "BLOCK I/O",
+      "THROTTLED",
+      "PSI CPU/MEM/IO"
+    )?;
+    for sample in samples.iter_mut() {
+      // The container may exit after it was checked, it's dropped on the
+      // next refresh then.
+      let stats = match sample.cgroup.stats() {
+        Ok(stats) => stats,
+        Err(err) => {
+          debug!(
+            "Failed to read the statistics of {}: {}",
+            sample.state.id, err
+          );
+          continue;
+        }
+      };
+      let now = Instant::now();
+      let elapsed = now.duration_since(sample.time).as_micros() as f64;
+      let cpu_delta = stats
+        .cpu
+        .usage_usec
+        .saturating_sub(sample.stats.cpu.usage_usec);
+      writeln!(
+        &mut stdout,
+        "{}",
+        row(&sample.state.id, &stats, cpu_delta as f64 / elapsed)
+      )?;
+
+      sample.stats = stats;
+      sample.time = now;
+    }
+    stdout.flush()?;
+
+    if !stream {
+      return Ok(());
+    }
+  }
+}
+
+/// Format a single line of the table. The CPU usage is given as a share of a
+/// single CPU.
+fn row(id: &str, stats: &Stats, cpu: f64) -> String {
+  let memory = &stats.memory;
+  let (limit, memory_percent) = match memory.limit {
+    Some(limit) if limit > 0 => (
+      format_bytes(limit),
+      format!("{:.2}%", memory.usage as f64 * 100.0 / limit as f64),
+    ),
+    _ => ("unlimited".to_string(), "-".to_string()),
+  };
+  let pressure = match &stats.pressure {
+    Some(pressure) => [&pressure.cpu, &pressure.memory, &pressure.io]
+      .iter()
+      .map(|pressure| match pressure {
+        Some(pressure) => format!("{:.2}", pressure.avg10),
+        None => "-".to_string(),
+      })
+      .collect::<Vec<_>>()
+      .join("/"),
+    None => "-".to_string(),
+  };
+
+  format!(
+    "{:<12}  {:>6.2}%  {:>21}  {:>7}  {:>6}  {:>21}  {:>8.1}s  {:>17}",
+    &id[..12],
+    cpu * 100.0,
+    format!("{} / {}", format_bytes(memory.usage), limit),
+    memory_percent,
+    stats.pids.current,
+    format!(
+      "{} / {}",
+      format_bytes(stats.io.read_bytes),
+      format_bytes(stats.io.write_bytes)
+    ),
+    stats.cpu.throttled_usec as f64 / 1_000_000.0,
+    pressure,
+  )
+}
+
+fn format_bytes(bytes: u64) -> String {
+  const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+  let mut value = bytes as f64;
+  let mut unit = 0;
+  while value >= 1024.0 && unit < UNITS.len() - 1 {
+    value /= 1024.0;
+    unit += 1;
+  }
+  format!("{:.1}{}", value, UNITS[unit])
+}
@@ -0,0 +1,179 @@
+use crate::cgroups::{self, Cgroup};
+use crate::console::{console_path, Frame};
+use crate::images;
+use crate::state::{self, ContainerState, StateError, Status};
+use crate::Result;
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::os::unix::net::UnixStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long the container gets to exit after the stop signal, in seconds.
+const DEFAULT_STOP_TIMEOUT: u64 = 10;
+/// How long we wait for the exit to be recorded once the container got
+/// killed.
+const KILL_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub enum StopError {
+  InvalidSignal(String),
+  InvalidTimeout(String),
+  KillTimeout(String),
+}
+
+impl fmt::Display for StopError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      StopError::InvalidSignal(signal) => write!(f, "Invalid signal: {}", signal),
+      StopError::InvalidTimeout(timeout) => write!(f, "Invalid stop timeout: {}", timeout),
+      StopError::KillTimeout(id) => write!(f, "Container {} didn't exit after SIGKILL", id),
+    }
+  }
+}
+
+impl Error for StopError {}
+
+/// Parse a signal by its name, with or without the `SIG` prefix, or by its
+/// number.
+pub fn parse_signal(value: &str) -> Result<Signal> {
+  let invalid = || StopError::InvalidSignal(value.to_string());
+  if let Ok(number) = value.parse::<i32>() {
+    return Ok(Signal::try_from(number).map_err(|_| invalid())?);
+  }
+
+  let name = value.to_uppercase();
+  let name = if name.starts_with("SIG") {
+    name
+  } else {
+    format!("SIG{}", name)
+  };
+  Ok(name.parse().map_err(|_| invalid())?)
+}
+
+/// The signal that stops the container gracefully. `--stop-signal` wins over
+/// the `StopSignal` of the image, and it's `SIGTERM` otherwise.
+pub fn from_args(args: &clap::ArgMatches) -> Result<Signal> {
+  match args.value_of("stop-signal") {
+    Some(signal) => parse_signal(signal),
+    None => match images::load_config(args.value_of("image").unwrap())?
+      .config
+      .stop_signal
+    {
+      Some(signal) => parse_signal(&signal),
+      None => Ok(Signal::SIGTERM),
+    },
+  }
+}
+
+/// Main entry for the stop subcommand. Sends the stop signal to the init
+/// process of a container, and kills every process of it if it's still
+/// running after the timeout. A stopped container isn't restarted.
+pub fn stop(args: &clap::ArgMatches<'static>) -> Result<()> {
+  let state = state::find(args.value_of("id").unwrap())?;
+  if !state.is_running() && !state.is_restarting() {
+    return Err(Box::new(StateError::NotRunning(state.id)));
+  }
+  let timeout = match args.value_of("time") {
+    Some(time) => time
+      .parse()
+      .map_err(|_| StopError::InvalidTimeout(time.to_string()))?,
+    None => DEFAULT_STOP_TIMEOUT,
+  };
+  let stop_signal = parse_signal(&state.stop_signal)?;
+  let cgroup = cgroups::new(&state.id)?;
+
+  // Frozen processes can't handle the signal until they are thawed.
+  if state.status == Status::Paused {
+    cgroup.thaw()?;
+  }
+  info!(
+    "Stopping the container {} with {}",
+    state.id,
+    stop_signal.as_str()
+  );
+  // The shim sends the signal, and knows not to restart the container then.
+  if let Err(err) = request_stop(&state.id, stop_signal) {
+    warn!("Failed to reach the shim of the container: {}", err);
+    if let Err(err) = signal::kill(Pid::from_raw(state.pid), stop_signal) {
+      debug!("Failed to send {}: {}", stop_signal.as_str(), err);
+    }
+  }
+
+  if !wait_for_exit(&state.id, &*cgroup, Duration::from_secs(timeout)) {
+    warn!(
+      "The container {} didn't stop in {}s, killing it",
+      state.id, timeout
+    );
+    for pid in cgroup.tasks()? {
+      let _ = signal::kill(pid, Signal::SIGKILL);
+    }
+    if !wait_for_exit(&state.id, &*cgroup, KILL_TIMEOUT) {
+      return Err(Box::new(StopError::KillTimeout(state.id)));
+    }
+  }
+  println!("{}", state.id);
+  Ok(())
+}
+
+/// Main entry for the kill subcommand. Sends a signal, `SIGKILL` by default,
+/// to the init process of a running container. Paused containers can only be
+/// killed, they don't handle any other signal until they are unpaused.
+pub fn kill(args: &clap::ArgMatches<'static>) -> Result<()> {
+  let state = state::find_running(args.value_of("id").unwrap())?;
+  let signal = match args.value_of("signal") {
+    Some(signal) => parse_signal(signal)?,
+    None => Signal::SIGKILL,
+  };
+  let paused = state.status == Status::Paused;
+  if paused && signal != Signal::SIGKILL {
+    return Err(Box::new(StateError::Paused(state.id)));
+  }
+
+  signal::kill(Pid::from_raw(state.pid), signal)?;
+  // Frozen processes only die once they are thawed.
+  if paused {
+    cgroups::new(&state.id)?.thaw()?;
+  }
+  info!("Sent {} to the container {}", signal.as_str(), state.id);
+  println!("{}", state.id);
+  Ok(())
+}
+
+fn request_stop(id: &str, signal: Signal) -> Result<()> {
+  let mut console = UnixStream::connect(console_path(id))?;
+  Frame::Stop(signal as i32).write(&mut console)?;
+  Ok(())
+}
+
+/// Wait until the supervisor of the container recorded its exit, or removed
+/// it. Nothing records the exit once the supervisor is gone, the processes
+/// of the container being gone is all there is to wait for then. Returns
+/// whether the container exited before the timeout.
+fn wait_for_exit(id: &str, cgroup: &dyn Cgroup, timeout: Duration) -> bool {
+  let start = Instant::now();
+  loop {
+    match ContainerState::load(id) {
+      Ok(state) if state.status == Status::Exited => return true,
+      Ok(state) if state.has_shim() => {}
+      Ok(state) if state.is_running() && has_tasks(cgroup) => {}
+      _ => return true,
+    }
+    if start.elapsed() >= timeout {
+      return false;
+    }
+    thread::sleep(POLL_INTERVAL);
+  }
+}
+
+fn has_tasks(cgroup: &dyn Cgroup) -> bool {
+  match cgroup.tasks() {
+    Ok(tasks) => !tasks.is_empty(),
+    // Assume the worst, we kill what's left after the timeout.
+    Err(_) => true,
+  }
+}
@@ -0,0 +1,280 @@
+use crate::cgroups::{self, OomWatcher};
+use crate::console::{console_path, Console, Frame};
+use crate::container::Container;
+use crate::fs::get_container_path;
+use crate::health::Monitor;
+use crate::logs::{Logger, Stream};
+use crate::network;
+use crate::restart::Backoff;
+use crate::state::{self, ContainerState, ExitStatus, Status};
+use crate::Result;
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::{close, dup, dup2, fork, setsid, ForkResult};
+use std::error::Error;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::process;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+const SUPERVISOR_LOG: &str = "supervisor.log";
+/// How often the stop command is looked for while waiting to restart.
+const STOP_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub enum SupervisorError {
+  Failed(String),
+  Disconnected,
+}
+
+impl fmt::Display for SupervisorError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      SupervisorError::Failed(message) => write!(f, "Failed to create the container: {}", message),
+      SupervisorError::Disconnected => {
+        write!(f, "The supervisor of the container exited unexpectedly")
+      }
+    }
+  }
+}
+
+impl Error for SupervisorError {}
+
+/// Start the supervisor, or shim, of a new container. The supervisor creates
+/// the container, holds its standard streams, reaps it, records its exit and
+/// cleans up after it, so that the container doesn't depend on us once we
+/// detach or exit. Returns the ID of the
+/// container and a connection to its console.
+pub fn start(args: &clap::ArgMatches<'static>) -> Result<(String, UnixStream)> {
+  // The first client is connected before the container starts, so that it
+  // doesn't miss any output.
+  let (mut client, server) = UnixStream::pair()?;
+  match fork()? {
+    ForkResult::Child => {
+      drop(client);
+      let code = match supervise(args, server) {
+        Ok(()) => 0,
+        Err(err) => {
+          error!("{}", err);
+          1
+        }
+      };
+      process::exit(code);
+    }
+    ForkResult::Parent { .. } => {
+      drop(server);
+      match Frame::read(&mut client)? {
+        Some(Frame::Ready(id)) => Ok((id, client)),
+        Some(Frame::Failed(message)) => Err(Box::new(SupervisorError::Failed(message))),
+        _ => Err(Box::new(SupervisorError::Disconnected)),
+      }
+    }
+  }
+}
+
+fn supervise(args: &clap::ArgMatches<'static>, mut client: UnixStream) -> Result<()> {
+  // Leave the session of the user, the signals of its terminal are not for
+  // us.
+  setsid()?;
+  let null = open("/dev/null", OFlag::O_RDWR, Mode::empty())?;
+  dup2(null, 0)?;
+  dup2(null, 1)?;
+  close(null)?;
+
+  let mut container = match Container::new(args) {
+    Ok(container) => container,
+    // The client reports the error.
+    Err(err) => return Ok(Frame::Failed(err.to_string()).write(&mut client)?),
+  };
+  // The terminal of the user can go away once it detaches.
+  let log = OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(get_container_path(&container.id).join(SUPERVISOR_LOG))?;
+  dup2(log.as_raw_fd(), 2)?;
+
+  let (input, terminal) = streams(&container);
+  let console = Arc::new(Console::new(
+    container.pid,
+    Logger::open(&container.state)?,
+    input,
+    terminal,
+  ));
+  let listener = UnixListener::bind(console_path(&container.id))?;
+  Frame::Ready(container.id.clone()).write(&mut client)?;
+  serve(&console, client);
+  let accept_console = Arc::clone(&console);
+  thread::spawn(move || {
+    for client in listener.incoming() {
+      match client {
+        Ok(client) => serve(&accept_console, client),
+        Err(err) => warn!("Failed to accept a console client: {}", err),
+      }
+    }
+  });
+
+  let mut backoff = Backoff::default();
+  loop {
+    let started = Instant::now();
+    let outputs = capture(&console, &container)?;
+    let health = Monitor::start(&container.state, container.pid);
+    let oom = OomWatcher::start(&*container.cgroup);
+    let mut status = container.wait();
+    if let Some(health) = health {
+      health.stop();
+    }
+    if oom.is_some_and(OomWatcher::stop) {
+      status.oom_killed = true;
+    }
+    info!(
+      "child process pid: {} status: {}",
+      i32::from(container.pid),
+      status
+    );
+    if status.oom_killed {
+      warn!("The container ran out of memory and got killed by the OOM killer");
+    }
+    // The output is captured until every process of the container is gone.
+    for output in outputs {
+      let _ = output.join();
+    }
+
+    let code = status.exit_code();
+    let restart = !console.is_stopped()
+      && container
+        .state
+        .restart_policy
+        .should_restart(code, container.state.restart_count);
+    record_exit(&container, status, restart, console.is_stopped())?;
+    let result = teardown(&container);
+    console.exit(code);
+    if let Err(err) = result {
+      if !restart {
+        return Err(err);
+      }
+      warn!("Failed to clean up after the container: {}", err);
+    }
+    if !restart {
+      break;
+    }
+
+    // The stop command interrupts the delay.
+    let delay = backoff.next(started.elapsed());
+    info!("Restarting the container in {:.2?}", delay);
+    let deadline = Instant::now() + delay;
+    while Instant::now() < deadline && !console.is_stopped() {
+      thread::sleep(STOP_INTERVAL);
+    }
+    if console.is_stopped() {
+      update_state(&container.state, |state| {
+        state.status = Status::Exited;
+        state.manually_stopped = true;
+      })?;
+      break;
+    }
+
+    let state = container.state.clone();
+    container = match container.restart() {
+      Ok(container) => container,
+      Err(err) => {
+        error!("Failed to restart the container: {}", err);
+        return update_state(&state, |state| state.status = Status::Exited);
+      }
+    };
+    let (input, terminal) = streams(&container);
+    console.restart(container.pid, input, terminal);
+  }
+
+  if container.state.auto_remove {
+    network::release(&container.state)?;
+    container.state.remove()?;
+  }
+  Ok(())
+}
+
+/// The input of the container and its terminal, as the console uses them.
+fn streams(container: &Container) -> (Option<RawFd>, Option<RawFd>) {
+  match (container.console, &container.pipes) {
+    (Some(master), _) if container.state.interactive => (Some(master), Some(master)),
+    (Some(master), _) => (None, Some(master)),
+    (None, Some(pipes)) => (pipes.stdin, None),
+    (None, None) => (None, None),
+  }
+}
+
+/// Capture the output of the container from threads.
+fn capture(console: &Arc<Console>, container: &Container) -> Result<Vec<JoinHandle<()>>> {
+  let mut outputs = vec![];
+  let mut capture = |source, stream| {
+    let console = Arc::clone(console);
+    outputs.push(thread::spawn(move || console.capture(source, stream)));
+  };
+  if let Some(master) = container.console {
+    // The master end stays open for the input of the clients.
+    capture(dup(master)?, Stream::Stdout);
+  }
+  if let Some(pipes) = &container.pipes {
+    capture(pipes.stdout, Stream::Stdout);
+    capture(pipes.stderr, Stream::Stderr);
+  }
+  Ok(outputs)
+}
+
+/// Record how the container exited in its state, which stays around after
+/// the shim is gone.
+fn record_exit(
+  container: &Container,
+  status: ExitStatus,
+  restart: bool,
+  stopped: bool,
+) -> Result<()> {
+  update_state(&container.state, |state| {
+    state.status = if restart {
+      Status::Restarting
+    } else {
+      Status::Exited
+    };
+    state.finished = Some(
+      SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs(),
+    );
+    state.exit_status = Some(status);
+    state.manually_stopped = stopped;
+  })
+}
+
+/// Change the recorded state of the container. Other subcommands may have
+/// changed it meanwhile, it's changed under their lock.
+fn update_state<F>(state: &ContainerState, change: F) -> Result<()>
+where
+  F: FnOnce(&mut ContainerState),
+{
+  let _lock = state::lock(&state.id)?;
+  let mut state = ContainerState::load(&state.id).unwrap_or_else(|_| state.clone());
+  change(&mut state);
+  state.save()
+}
+
+/// Send the output of the container to the client, and its input to the
+/// container from a thread.
+fn serve(console: &Arc<Console>, client: UnixStream) {
+  if let Err(err) = console.add_client(&client) {
+    warn!("Failed to attach a console client: {}", err);
+    return;
+  }
+  let console = Arc::clone(console);
+  thread::spawn(move || console.serve(client));
+}
+
+/// Teardown function after the container process. This handles the network
+/// and the cgroups of the container.
+fn teardown(container: &Container) -> Result<()> {
+  network::teardown(&container.state)?;
+  cgroups::destroy(&*container.cgroup)
+}
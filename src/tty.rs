@@ -0,0 +1,164 @@
+use crate::Result;
+use nix::errno::Errno;
+use nix::fcntl::{open, OFlag};
+use nix::libc;
+use nix::poll::PollFd;
+use nix::pty::{grantpt, posix_openpt, ptsname_r, unlockpt, Winsize};
+use nix::sys::socket::{
+  recvmsg, sendmsg, socketpair, AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags,
+  SockFlag, SockType,
+};
+use nix::sys::stat::Mode;
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg, Termios};
+use nix::sys::uio::IoVec;
+use nix::unistd::{close, dup2, setsid, write};
+use std::error::Error;
+use std::fmt;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+const STDIN: RawFd = 0;
+
+#[derive(Debug)]
+pub enum TtyError {
+  NoConsole,
+}
+
+impl fmt::Display for TtyError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TtyError::NoConsole => write!(f, "The container didn't send its terminal"),
+    }
+  }
+}
+
+impl Error for TtyError {}
+
+/// A connected pair of sockets. The container allocates its pseudo-terminal
+/// from its own `/dev/pts`, so that the terminal has a name inside the
+/// container, and sends us the master end over the second socket.
+pub fn console_socket() -> Result<(RawFd, RawFd)> {
+  Ok(socketpair(
+    AddressFamily::Unix,
+    SockType::Stream,
+    None,
+    SockFlag::SOCK_CLOEXEC,
+  )?)
+}
+
+/// Receive the master end of the pseudo-terminal of the container.
+pub fn receive_console(socket: RawFd) -> Result<RawFd> {
+  let mut buf = [0; 1];
+  let mut fds = nix::cmsg_space!([RawFd; 1]);
+  let message = recvmsg(
+    socket,
+    &[IoVec::from_mut_slice(&mut buf)],
+    Some(&mut fds),
+    MsgFlags::empty(),
+  )?;
+  for control in message.cmsgs() {
+    if let ControlMessageOwned::ScmRights(fds) = control {
+      if let Some(master) = fds.first() {
+        return Ok(*master);
+      }
+    }
+  }
+  Err(Box::new(TtyError::NoConsole))
+}
+
+/// The window size of the terminal, if the descriptor is one.
+pub fn window_size(fd: RawFd) -> Option<Winsize> {
+  let mut size: Winsize = unsafe { std::mem::zeroed() };
+  match unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) } {
+    0 => Some(size),
+    _ => None,
+  }
+}
+
+/// Resize the pseudo-terminal, which sends SIGWINCH to the foreground
+/// processes of the container.
+pub fn set_window_size(master: RawFd, size: &Winsize) {
+  unsafe { libc::ioctl(master, libc::TIOCSWINSZ, size) };
+}
+
+/// Set up the standard streams of the container process. With a console
+/// socket, the process allocates a pseudo-terminal, sends the master end over
+/// the socket and starts a new session with the slave end as its controlling
+/// terminal. Without `interactive`, the process reads nothing.
+pub fn setup_child(console: Option<RawFd>, interactive: bool) -> Result<()> {
+  if let Some(console) = console {
+    let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY)?;
+    grantpt(&master)?;
+    unlockpt(&master)?;
+    let slave = open(
+      ptsname_r(&master)?.as_str(),
+      OFlag::O_RDWR | OFlag::O_NOCTTY,
+      Mode::empty(),
+    )?;
+    sendmsg(
+      console,
+      &[IoVec::from_slice(&[0])],
+      &[ControlMessage::ScmRights(&[master.as_raw_fd()])],
+      MsgFlags::empty(),
+      None,
+    )?;
+    close(console)?;
+    drop(master);
+
+    setsid()?;
+    if unsafe { libc::ioctl(slave, libc::TIOCSCTTY, 0) } < 0 {
+      return Err(Box::new(nix::Error::last()));
+    }
+    for fd in 0..3 {
+      dup2(slave, fd)?;
+    }
+    close(slave)?;
+  }
+
+  if !interactive {
+    let null = open("/dev/null", OFlag::O_RDONLY, Mode::empty())?;
+    dup2(null, STDIN)?;
+    close(null)?;
+  }
+  Ok(())
+}
+
+/// Our terminal in raw mode, so that every key goes to the container as it
+/// is, including Ctrl-C. The previous mode is restored on drop.
+pub struct RawMode {
+  original: Termios,
+}
+
+impl RawMode {
+  pub fn enable() -> Result<Self> {
+    let original = tcgetattr(STDIN)?;
+    let mut raw = original.clone();
+    cfmakeraw(&mut raw);
+    tcsetattr(STDIN, SetArg::TCSANOW, &raw)?;
+    Ok(RawMode { original })
+  }
+}
+
+impl Drop for RawMode {
+  fn drop(&mut self) {
+    let _ = tcsetattr(STDIN, SetArg::TCSANOW, &self.original);
+  }
+}
+
+pub fn write_all(fd: RawFd, mut buf: &[u8]) -> nix::Result<()> {
+  while !buf.is_empty() {
+    match write(fd, buf) {
+      Ok(written) => buf = &buf[written..],
+      Err(nix::Error::Sys(Errno::EINTR)) => {}
+      Err(err) => return Err(err),
+    }
+  }
+  Ok(())
+}
+
+/// Whether the descriptor has data to read, or got closed.
+pub fn is_ready(fd: &PollFd) -> bool {
+  match fd.revents() {
+    Some(events) => !events.is_empty(),
+    None => false,
+  }
+}
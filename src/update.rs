@@ -0,0 +1,14 @@
+use crate::cgroups;
+use crate::state;
+use crate::Result;
+
+/// Main entry for the update subcommand. Rewrites the given cgroup limits of
+/// a running container and records them in its state.
+pub fn update(args: &clap::ArgMatches<'static>) -> Result<()> {
+  let id = state::find_running(args.value_of("id").unwrap())?.id;
+  state::update_running(&id, |state| {
+    let changes = state.resources.update(args)?;
+    info!("Updating the resources of {}: {:?}", state.id, changes);
+    cgroups::new(&state.id)?.apply(&changes)
+  })
+}